@@ -0,0 +1,395 @@
+//! Build flash loan transactions wrapping arbitrary instructions
+
+use std::fmt;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::Hash;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::message::{Message, VersionedMessage};
+use flash_loan_mastery::{math, PoolConfig, LOAN_FEE, REFERRAL_FEE};
+
+use crate::versioned::{compile_v0_message, AddressLookupTableAccount, CompileError};
+use crate::{borrow_ix, repay_ix};
+
+/// The maximum size of a serialized transaction
+// this is the IPv6 MTU minus the IPv6 and UDP headers, same as `solana_sdk::packet::PACKET_DATA_SIZE`
+pub const PACKET_DATA_SIZE: usize = 1280 - 40 - 8;
+
+/// Get the amount that `repay` must be called with for a loan of `amount`
+///
+/// This matches what `borrow` demands, the referral fee is always included.
+#[must_use]
+pub fn repay_amount(amount: u64, loan_fee: u64, referral_fee: u64) -> Option<u64> {
+    math::repayment_amount(amount, loan_fee, referral_fee).ok()
+}
+
+/// Errors returned when building a flash loan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashLoanBuilderError {
+    /// The loan amount is zero
+    ZeroAmount,
+    /// No borrower was set
+    MissingBorrower,
+    /// No pool share mint was set
+    MissingPoolShareMint,
+    /// The repayment amount does not fit in a `u64`
+    RepaymentOverflow,
+}
+
+impl fmt::Display for FlashLoanBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroAmount => write!(f, "the loan amount is zero"),
+            Self::MissingBorrower => write!(f, "no borrower was set"),
+            Self::MissingPoolShareMint => write!(f, "no pool share mint was set"),
+            Self::RepaymentOverflow => write!(f, "the repayment amount overflows"),
+        }
+    }
+}
+
+impl std::error::Error for FlashLoanBuilderError {}
+
+/// Builds the `[borrow, ...instructions, repay]` sequence of a flash loan
+#[derive(Debug, Clone)]
+pub struct FlashLoanBuilder {
+    mint: Pubkey,
+    amount: u64,
+    loan_fee: u64,
+    referral_fee: u64,
+    pool_share_mint: Option<Pubkey>,
+    borrower: Option<(Pubkey, Pubkey)>,
+    referrer: Option<Pubkey>,
+    instructions: Vec<Instruction>,
+}
+
+impl FlashLoanBuilder {
+    /// Start building a flash loan of `amount` from the pool lending `mint`
+    ///
+    /// The default fees are assumed until [`FlashLoanBuilder::fees`] or
+    /// [`FlashLoanBuilder::pool_config`] is used.
+    #[must_use]
+    pub fn new(mint: Pubkey, amount: u64) -> Self {
+        Self {
+            mint,
+            amount,
+            loan_fee: LOAN_FEE,
+            referral_fee: REFERRAL_FEE,
+            pool_share_mint: None,
+            borrower: None,
+            referrer: None,
+            instructions: vec![],
+        }
+    }
+
+    /// Set the entity borrowing and repaying the loan, and its token account for `mint`
+    #[must_use]
+    pub fn borrower(mut self, borrower: Pubkey, token_account: Pubkey) -> Self {
+        self.borrower = Some((borrower, token_account));
+        self
+    }
+
+    /// Set the mint of the token representing shares in the pool
+    #[must_use]
+    pub fn pool_share_mint(mut self, pool_share_mint: Pubkey) -> Self {
+        self.pool_share_mint = Some(pool_share_mint);
+        self
+    }
+
+    /// Set the token account receiving the referral fee
+    #[must_use]
+    pub fn referrer(mut self, referral_token_to: Pubkey) -> Self {
+        self.referrer = Some(referral_token_to);
+        self
+    }
+
+    /// Set the fees charged by the pool, in millionths of the borrowed amount
+    #[must_use]
+    pub fn fees(mut self, loan_fee: u64, referral_fee: u64) -> Self {
+        self.loan_fee = loan_fee;
+        self.referral_fee = referral_fee;
+        self
+    }
+
+    /// Use the fees from the `PoolConfig` of the pool
+    #[must_use]
+    pub fn pool_config(self, pool_config: &PoolConfig) -> Self {
+        self.fees(
+            pool_config.loan_fee_millionths,
+            pool_config.referral_fee_millionths,
+        )
+    }
+
+    /// Add instructions to run between `borrow` and `repay`
+    #[must_use]
+    pub fn wrap(mut self, instructions: Vec<Instruction>) -> Self {
+        self.instructions.extend(instructions);
+        self
+    }
+
+    /// Build the flash loan
+    pub fn build(self) -> std::result::Result<FlashLoan, FlashLoanBuilderError> {
+        if self.amount == 0 {
+            return Err(FlashLoanBuilderError::ZeroAmount);
+        }
+        let (borrower, token_account) = self
+            .borrower
+            .ok_or(FlashLoanBuilderError::MissingBorrower)?;
+        let pool_share_mint = self
+            .pool_share_mint
+            .ok_or(FlashLoanBuilderError::MissingPoolShareMint)?;
+        let repayment = repay_amount(self.amount, self.loan_fee, self.referral_fee)
+            .ok_or(FlashLoanBuilderError::RepaymentOverflow)?;
+
+        let mut instructions = Vec::with_capacity(self.instructions.len() + 2);
+        instructions.push(borrow_ix(
+            &borrower,
+            &self.mint,
+            &token_account,
+            self.amount,
+        ));
+        instructions.extend(self.instructions);
+        instructions.push(repay_ix(
+            &borrower,
+            &self.mint,
+            &pool_share_mint,
+            &token_account,
+            repayment,
+            self.referrer.as_ref(),
+        ));
+
+        let flash_loan = FlashLoan {
+            payer: borrower,
+            instructions,
+            repay_amount: repayment,
+        };
+        let transaction_size = flash_loan.transaction_size();
+        if transaction_size > PACKET_DATA_SIZE {
+            log::warn!(
+                "flash loan transaction is {} bytes, which exceeds the {} byte packet limit",
+                transaction_size,
+                PACKET_DATA_SIZE
+            );
+        }
+
+        Ok(flash_loan)
+    }
+}
+
+/// A flash loan built by [`FlashLoanBuilder`]
+#[derive(Debug, Clone)]
+pub struct FlashLoan {
+    /// The fee payer, this is the borrower
+    pub payer: Pubkey,
+    /// The `[borrow, ...instructions, repay]` sequence
+    pub instructions: Vec<Instruction>,
+    /// The amount passed to `repay`
+    pub repay_amount: u64,
+}
+
+impl FlashLoan {
+    /// Get the size of a legacy transaction containing only this flash loan
+    #[must_use]
+    pub fn transaction_size(&self) -> usize {
+        let message = Message::new(&self.instructions, Some(&self.payer));
+        let num_signatures = usize::from(message.header.num_required_signatures);
+        short_vec_len(num_signatures) + num_signatures * 64 + message.serialize().len()
+    }
+
+    /// Whether a legacy transaction containing this flash loan fits in a single packet
+    #[must_use]
+    pub fn fits_in_packet(&self) -> bool {
+        self.transaction_size() <= PACKET_DATA_SIZE
+    }
+
+    /// Compile this flash loan into a v0 message, loading accounts from `lookup_tables`
+    pub fn to_v0_message(
+        &self,
+        lookup_tables: &[AddressLookupTableAccount],
+        recent_blockhash: Hash,
+    ) -> std::result::Result<VersionedMessage, CompileError> {
+        compile_v0_message(
+            &self.payer,
+            &self.instructions,
+            lookup_tables,
+            recent_blockhash,
+        )
+    }
+}
+
+/// The size of the compact-u16 length prefix used when serializing transactions
+pub(crate) fn short_vec_len(len: usize) -> usize {
+    match len {
+        0..=0x7f => 1,
+        0x80..=0x3fff => 2,
+        _ => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    use anchor_lang::solana_program::instruction::AccountMeta;
+    use anchor_lang::InstructionData;
+    use flash_loan_mastery::FEE_DENOMINATOR;
+
+    use super::*;
+    use crate::{find_pool_authority, pool_vault_address};
+
+    thread_local! {
+        static WARNINGS: RefCell<Vec<String>> = RefCell::new(vec![]);
+    }
+
+    /// Records warnings logged on the current thread
+    struct WarningLogger;
+
+    impl log::Log for WarningLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.with(|warnings| warnings.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn take_warnings() -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&WarningLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Warn);
+        });
+        WARNINGS.with(|warnings| warnings.take())
+    }
+
+    fn noop_ix(accounts: usize) -> Instruction {
+        Instruction {
+            program_id: Pubkey::new_unique(),
+            accounts: (0..accounts)
+                .map(|_| AccountMeta::new(Pubkey::new_unique(), false))
+                .collect(),
+            data: vec![],
+        }
+    }
+
+    fn builder(amount: u64) -> FlashLoanBuilder {
+        FlashLoanBuilder::new(Pubkey::new_unique(), amount)
+            .borrower(Pubkey::new_unique(), Pubkey::new_unique())
+            .pool_share_mint(Pubkey::new_unique())
+    }
+
+    #[test]
+    fn test_instruction_sequence() {
+        let mint = Pubkey::new_unique();
+        let borrower = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let pool_share_mint = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let wrapped = vec![noop_ix(1), noop_ix(2), noop_ix(3)];
+        let flash_loan = FlashLoanBuilder::new(mint, 1_000_000)
+            .borrower(borrower, token_account)
+            .pool_share_mint(pool_share_mint)
+            .referrer(referrer)
+            .wrap(wrapped[..2].to_vec())
+            .wrap(wrapped[2..].to_vec())
+            .build()
+            .unwrap();
+
+        assert_eq!(flash_loan.payer, borrower);
+        assert_eq!(flash_loan.instructions.len(), wrapped.len() + 2);
+        assert_eq!(
+            flash_loan.instructions[0],
+            borrow_ix(&borrower, &mint, &token_account, 1_000_000)
+        );
+        assert_eq!(flash_loan.instructions[1..=wrapped.len()], wrapped[..]);
+        // `borrow` looks for `repay` after the wrapped instructions, at the end
+        let repay = flash_loan.instructions.last().unwrap();
+        assert_eq!(
+            *repay,
+            repay_ix(
+                &borrower,
+                &mint,
+                &pool_share_mint,
+                &token_account,
+                flash_loan.repay_amount,
+                Some(&referrer),
+            )
+        );
+        // the accounts `borrow` checks the repay against
+        assert_eq!(repay.accounts[2].pubkey, pool_vault_address(&mint));
+        assert_eq!(repay.accounts[3].pubkey, find_pool_authority(&mint).0);
+        assert_eq!(repay.accounts.last().unwrap().pubkey, referrer);
+    }
+
+    #[test]
+    fn test_repay_amount() {
+        let amount = 1_000_000_000;
+        let fee = u128::from(amount * (LOAN_FEE + REFERRAL_FEE)) / FEE_DENOMINATOR;
+        let expected = amount + u64::try_from(fee).unwrap();
+        assert_eq!(builder(amount).build().unwrap().repay_amount, expected);
+        assert_eq!(
+            repay_amount(amount, LOAN_FEE, REFERRAL_FEE),
+            math::repayment_amount(amount, LOAN_FEE, REFERRAL_FEE).ok()
+        );
+
+        // the fees of the pool are used
+        let flash_loan = builder(amount).fees(900, 100).build().unwrap();
+        assert_eq!(
+            flash_loan.repay_amount,
+            repay_amount(amount, 900, 100).unwrap()
+        );
+        assert_eq!(
+            flash_loan.instructions.last().unwrap().data,
+            flash_loan_mastery::instruction::Repay {
+                amount: flash_loan.repay_amount
+            }
+            .data()
+        );
+    }
+
+    #[test]
+    fn test_build_errors() {
+        assert_eq!(
+            builder(0).build().unwrap_err(),
+            FlashLoanBuilderError::ZeroAmount
+        );
+        assert_eq!(
+            FlashLoanBuilder::new(Pubkey::new_unique(), 1)
+                .pool_share_mint(Pubkey::new_unique())
+                .build()
+                .unwrap_err(),
+            FlashLoanBuilderError::MissingBorrower
+        );
+        assert_eq!(
+            FlashLoanBuilder::new(Pubkey::new_unique(), 1)
+                .borrower(Pubkey::new_unique(), Pubkey::new_unique())
+                .build()
+                .unwrap_err(),
+            FlashLoanBuilderError::MissingPoolShareMint
+        );
+        assert_eq!(
+            builder(u64::MAX).build().unwrap_err(),
+            FlashLoanBuilderError::RepaymentOverflow
+        );
+    }
+
+    #[test]
+    fn test_packet_size_warning() {
+        take_warnings();
+        let flash_loan = builder(1_000).wrap(vec![noop_ix(4)]).build().unwrap();
+        assert!(flash_loan.fits_in_packet());
+        assert!(take_warnings().is_empty());
+
+        let flash_loan = builder(1_000).wrap(vec![noop_ix(40)]).build().unwrap();
+        assert!(flash_loan.transaction_size() > PACKET_DATA_SIZE);
+        assert!(!flash_loan.fits_in_packet());
+        let warnings = take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains(&flash_loan.transaction_size().to_string()));
+    }
+}
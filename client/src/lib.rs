@@ -0,0 +1,672 @@
+#![warn(missing_debug_implementations, clippy::pedantic)]
+#![allow(clippy::module_name_repetitions, clippy::missing_errors_doc)]
+//! Rust client for the Flash Loan Mastery program
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::{bpf_loader_upgradeable, sysvar};
+use anchor_lang::{system_program, InstructionData};
+use flash_loan_mastery::{
+    CpiAllowlist, LoanReceipt, PoolAuthority, PoolConfig, PoolStats, CPI_ALLOWLIST_SEED,
+    EVENT_AUTHORITY_SEED, FLASH_LOAN_SIGNER_SEED, LOAN_RECEIPT_SEED, POOL_CONFIG_SEED, POOL_SEED,
+    POOL_STATS_SEED,
+};
+use spl_associated_token_account::get_associated_token_address;
+
+pub mod builder;
+pub mod versioned;
+
+pub use builder::{repay_amount, FlashLoan, FlashLoanBuilder, FlashLoanBuilderError};
+pub use flash_loan_mastery::ID as PROGRAM_ID;
+pub use versioned::{
+    compile_v0_message, create_pool_lookup_table_ixs, extend_pool_lookup_table_ix,
+    pool_lookup_table_addresses, versioned_transaction_size, AddressLookupTableAccount,
+};
+
+/// Find the pool authority PDA of the pool lending `mint`
+#[must_use]
+pub fn find_pool_authority(mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &PROGRAM_ID)
+}
+
+/// Find the pool config PDA of a pool
+#[must_use]
+pub fn find_pool_config(pool_authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_CONFIG_SEED, pool_authority.as_ref()], &PROGRAM_ID)
+}
+
+/// Find the pool stats PDA of a pool
+#[must_use]
+pub fn find_pool_stats(pool_authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_STATS_SEED, pool_authority.as_ref()], &PROGRAM_ID)
+}
+
+/// Find the loan receipt PDA of a pool, it only exists while a `borrow_with_receipt` is unsettled
+///
+/// A pool has a single receipt, so only one `borrow_with_receipt` of it can be unsettled at a time.
+#[must_use]
+pub fn find_loan_receipt(pool_authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LOAN_RECEIPT_SEED, pool_authority.as_ref()], &PROGRAM_ID)
+}
+
+/// Find the PDA listing the programs allowed to call `borrow` and `repay` of a pool through CPI
+#[must_use]
+pub fn find_cpi_allowlist(pool_authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CPI_ALLOWLIST_SEED, pool_authority.as_ref()], &PROGRAM_ID)
+}
+
+/// Find the event authority PDA used when the `event-cpi` feature is enabled
+#[must_use]
+pub fn find_event_authority() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &PROGRAM_ID)
+}
+
+/// Find the PDA that signs the calls of `flash_loan` to receivers
+#[must_use]
+pub fn find_flash_loan_signer() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[FLASH_LOAN_SIGNER_SEED], &PROGRAM_ID)
+}
+
+/// Get the token account holding the funds of the pool lending `mint`
+#[must_use]
+pub fn pool_vault_address(mint: &Pubkey) -> Pubkey {
+    get_associated_token_address(&find_pool_authority(mint).0, mint)
+}
+
+/// Get the extra accounts needed by instructions emitting self-CPI events
+///
+/// These should be appended to the instruction when the program is built with the `event-cpi`
+/// feature.
+#[must_use]
+pub fn event_cpi_account_metas() -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new_readonly(find_event_authority().0, false),
+        AccountMeta::new_readonly(PROGRAM_ID, false),
+    ]
+}
+
+/// Decode a `PoolAuthority` account
+pub fn decode_pool_authority(data: &[u8]) -> Result<PoolAuthority> {
+    decode_zero_copy(data)
+}
+
+/// Decode a `PoolConfig` account
+pub fn decode_pool_config(data: &[u8]) -> Result<PoolConfig> {
+    decode_zero_copy(data)
+}
+
+/// Decode a `PoolStats` account
+pub fn decode_pool_stats(data: &[u8]) -> Result<PoolStats> {
+    decode_zero_copy(data)
+}
+
+/// Decode a `LoanReceipt` account
+pub fn decode_loan_receipt(data: &[u8]) -> Result<LoanReceipt> {
+    decode_zero_copy(data)
+}
+
+/// Decode a `CpiAllowlist` account
+pub fn decode_cpi_allowlist(data: &[u8]) -> Result<CpiAllowlist> {
+    decode_zero_copy(data)
+}
+
+fn decode_zero_copy<T: anchor_lang::ZeroCopy + AccountDeserialize>(data: &[u8]) -> Result<T> {
+    // `try_deserialize` panics if the account size does not match
+    if data.len() != 8 + std::mem::size_of::<T>() {
+        return Err(error!(ErrorCode::AccountDidNotDeserialize));
+    }
+    T::try_deserialize(&mut &data[..])
+}
+
+/// Build an `init_pool` instruction
+#[must_use]
+pub fn init_pool_ix(
+    funder: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    pool_share_mint_authority: &Pubkey,
+    admin: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::InitPool {
+            funder: *funder,
+            mint: *mint,
+            pool_share_mint: *pool_share_mint,
+            pool_share_mint_authority: *pool_share_mint_authority,
+            pool_authority: find_pool_authority(mint).0,
+            token_program: spl_token::ID,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::InitPool { admin: *admin }.data(),
+    }
+}
+
+/// Build an `init_pool_config` instruction
+#[must_use]
+pub fn init_pool_config_ix(funder: &Pubkey, mint: &Pubkey) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::InitPoolConfig {
+            funder: *funder,
+            pool_authority,
+            pool_config: find_pool_config(&pool_authority).0,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::InitPoolConfig {}.data(),
+    }
+}
+
+/// Build an `init_cpi_allowlist` instruction
+#[must_use]
+pub fn init_cpi_allowlist_ix(funder: &Pubkey, mint: &Pubkey) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::InitCpiAllowlist {
+            funder: *funder,
+            pool_authority,
+            cpi_allowlist: find_cpi_allowlist(&pool_authority).0,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::InitCpiAllowlist {}.data(),
+    }
+}
+
+/// Build an `add_cpi_program` instruction allowing `program` to borrow from the pool through CPI
+#[must_use]
+pub fn add_cpi_program_ix(admin: &Pubkey, mint: &Pubkey, program: &Pubkey) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::UpdateCpiAllowlist {
+            admin: *admin,
+            pool_authority,
+            cpi_allowlist: find_cpi_allowlist(&pool_authority).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::AddCpiProgram { program: *program }.data(),
+    }
+}
+
+/// Build a `remove_cpi_program` instruction
+#[must_use]
+pub fn remove_cpi_program_ix(admin: &Pubkey, mint: &Pubkey, program: &Pubkey) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::RemoveCpiProgram { program: *program }.data(),
+        ..add_cpi_program_ix(admin, mint, program)
+    }
+}
+
+/// Build an `init_pool_stats` instruction
+#[must_use]
+pub fn init_pool_stats_ix(funder: &Pubkey, mint: &Pubkey) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::InitPoolStats {
+            funder: *funder,
+            pool_authority,
+            pool_stats: find_pool_stats(&pool_authority).0,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::InitPoolStats {}.data(),
+    }
+}
+
+/// Build a `set_pool_config` instruction updating the fees of a pool, in millionths of the
+/// borrowed amount
+#[must_use]
+pub fn set_pool_config_ix(
+    admin: &Pubkey,
+    mint: &Pubkey,
+    loan_fee_millionths: u64,
+    referral_fee_millionths: u64,
+) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::SetPoolConfig {
+            admin: *admin,
+            pool_authority,
+            pool_config: find_pool_config(&pool_authority).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::SetPoolConfig {
+            loan_fee_millionths,
+            referral_fee_millionths,
+        }
+        .data(),
+    }
+}
+
+/// Build a `migrate_pool_authority` instruction, anyone can migrate a pool
+#[must_use]
+pub fn migrate_pool_authority_ix(funder: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::MigratePoolAuthority {
+            funder: *funder,
+            mint: *mint,
+            pool_authority: find_pool_authority(mint).0,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::MigratePoolAuthority {}.data(),
+    }
+}
+
+/// Build an `init_pool_admin` instruction, signed by the upgrade authority of the program
+#[must_use]
+pub fn init_pool_admin_ix(
+    upgrade_authority: &Pubkey,
+    mint: &Pubkey,
+    admin: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::InitPoolAdmin {
+            upgrade_authority: *upgrade_authority,
+            pool_authority: find_pool_authority(mint).0,
+            program: PROGRAM_ID,
+            program_data: Pubkey::find_program_address(
+                &[PROGRAM_ID.as_ref()],
+                &bpf_loader_upgradeable::ID,
+            )
+            .0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::InitPoolAdmin { admin: *admin }.data(),
+    }
+}
+
+/// Build a `propose_admin` instruction
+#[must_use]
+pub fn propose_admin_ix(admin: &Pubkey, mint: &Pubkey, new_admin: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::ProposeAdmin {
+            admin: *admin,
+            pool_authority: find_pool_authority(mint).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::ProposeAdmin {
+            new_admin: *new_admin,
+        }
+        .data(),
+    }
+}
+
+/// Build an `accept_admin` instruction
+#[must_use]
+pub fn accept_admin_ix(pending_admin: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::AcceptAdmin {
+            pending_admin: *pending_admin,
+            pool_authority: find_pool_authority(mint).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::AcceptAdmin {}.data(),
+    }
+}
+
+/// Build a `set_guardian` instruction
+#[must_use]
+pub fn set_guardian_ix(admin: &Pubkey, mint: &Pubkey, guardian: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::SetGuardian {
+            admin: *admin,
+            pool_authority: find_pool_authority(mint).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::SetGuardian {
+            guardian: *guardian,
+        }
+        .data(),
+    }
+}
+
+/// Build a `pause` instruction, signed by the guardian or the admin of the pool
+#[must_use]
+pub fn pause_ix(guardian: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Pause {
+            guardian: *guardian,
+            pool_authority: find_pool_authority(mint).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Pause {}.data(),
+    }
+}
+
+/// Build an `unpause` instruction
+#[must_use]
+pub fn unpause_ix(admin: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Unpause {
+            admin: *admin,
+            pool_authority: find_pool_authority(mint).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Unpause {}.data(),
+    }
+}
+
+/// Build a `deposit` instruction
+#[must_use]
+pub fn deposit_ix(
+    depositor: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_from: &Pubkey,
+    pool_share_token_to: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Deposit {
+            depositor: *depositor,
+            token_from: *token_from,
+            token_to: pool_vault_address(mint),
+            pool_share_token_to: *pool_share_token_to,
+            pool_share_mint: *pool_share_mint,
+            pool_authority: find_pool_authority(mint).0,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Deposit { amount }.data(),
+    }
+}
+
+/// Build a `deposit_with_min_shares` instruction
+#[must_use]
+pub fn deposit_with_min_shares_ix(
+    depositor: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_from: &Pubkey,
+    pool_share_token_to: &Pubkey,
+    amount: u64,
+    min_shares: u64,
+) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::DepositWithMinShares { amount, min_shares }.data(),
+        ..deposit_ix(
+            depositor,
+            mint,
+            pool_share_mint,
+            token_from,
+            pool_share_token_to,
+            amount,
+        )
+    }
+}
+
+/// Build a `mint_shares` instruction, `share_amount` is the exact amount of pool shares to mint
+#[must_use]
+pub fn mint_shares_ix(
+    depositor: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_from: &Pubkey,
+    pool_share_token_to: &Pubkey,
+    share_amount: u64,
+    max_tokens: u64,
+) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::MintShares {
+            share_amount,
+            max_tokens,
+        }
+        .data(),
+        ..deposit_ix(
+            depositor,
+            mint,
+            pool_share_mint,
+            token_from,
+            pool_share_token_to,
+            max_tokens,
+        )
+    }
+}
+
+/// Build a `withdraw` instruction, `amount` is the amount of pool shares to redeem
+#[must_use]
+pub fn withdraw_ix(
+    withdrawer: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_to: &Pubkey,
+    pool_share_token_from: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Withdraw {
+            withdrawer: *withdrawer,
+            token_from: pool_vault_address(mint),
+            token_to: *token_to,
+            pool_share_token_from: *pool_share_token_from,
+            pool_share_mint: *pool_share_mint,
+            pool_authority: find_pool_authority(mint).0,
+            token_program: spl_token::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Withdraw { amount }.data(),
+    }
+}
+
+/// Build a `withdraw_with_min_tokens` instruction, `amount` is the amount of pool shares to redeem
+#[must_use]
+pub fn withdraw_with_min_tokens_ix(
+    withdrawer: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_to: &Pubkey,
+    pool_share_token_from: &Pubkey,
+    amount: u64,
+    min_tokens: u64,
+) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::WithdrawWithMinTokens { amount, min_tokens }.data(),
+        ..withdraw_ix(
+            withdrawer,
+            mint,
+            pool_share_mint,
+            token_to,
+            pool_share_token_from,
+            amount,
+        )
+    }
+}
+
+/// Build a `withdraw_exact_tokens` instruction, `token_amount` is the exact amount of tokens to receive
+#[must_use]
+pub fn withdraw_exact_tokens_ix(
+    withdrawer: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_to: &Pubkey,
+    pool_share_token_from: &Pubkey,
+    token_amount: u64,
+    max_shares: u64,
+) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::WithdrawExactTokens {
+            token_amount,
+            max_shares,
+        }
+        .data(),
+        ..withdraw_ix(
+            withdrawer,
+            mint,
+            pool_share_mint,
+            token_to,
+            pool_share_token_from,
+            max_shares,
+        )
+    }
+}
+
+/// Build a `borrow` instruction
+///
+/// When a program on the CPI allowlist of the pool calls `borrow`, the allowlist account found
+/// with [`find_cpi_allowlist`] must be appended to the instruction.
+#[must_use]
+pub fn borrow_ix(borrower: &Pubkey, mint: &Pubkey, token_to: &Pubkey, amount: u64) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Borrow {
+            borrower: *borrower,
+            token_from: pool_vault_address(mint),
+            token_to: *token_to,
+            pool_authority,
+            instructions_sysvar: sysvar::instructions::ID,
+            token_program: spl_token::ID,
+            pool_config: find_pool_config(&pool_authority).0,
+            pool_stats: find_pool_stats(&pool_authority).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Borrow { amount }.data(),
+    }
+}
+
+/// Build a `borrow_tranche` instruction, the repay of the loan it extends must also cover it
+#[must_use]
+pub fn borrow_tranche_ix(
+    borrower: &Pubkey,
+    mint: &Pubkey,
+    token_to: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        data: flash_loan_mastery::instruction::BorrowTranche { amount }.data(),
+        ..borrow_ix(borrower, mint, token_to, amount)
+    }
+}
+
+/// Build a `borrow_with_receipt` instruction, the loan must be followed by a `settle` instruction
+#[must_use]
+pub fn borrow_with_receipt_ix(
+    borrower: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_to: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::BorrowWithReceipt {
+            borrower: *borrower,
+            token_from: pool_vault_address(mint),
+            token_to: *token_to,
+            pool_authority,
+            instructions_sysvar: sysvar::instructions::ID,
+            token_program: spl_token::ID,
+            pool_config: find_pool_config(&pool_authority).0,
+            pool_stats: find_pool_stats(&pool_authority).0,
+            pool_share_mint: *pool_share_mint,
+            loan_receipt: find_loan_receipt(&pool_authority).0,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::BorrowWithReceipt { amount }.data(),
+    }
+}
+
+/// Build a `settle` instruction, closing the loan receipt of `borrower`
+#[must_use]
+pub fn settle_ix(borrower: &Pubkey, mint: &Pubkey, pool_share_mint: &Pubkey) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::Settle {
+            borrower: *borrower,
+            loan_receipt: find_loan_receipt(&pool_authority).0,
+            token_to: pool_vault_address(mint),
+            pool_authority,
+            pool_share_mint: *pool_share_mint,
+            pool_stats: find_pool_stats(&pool_authority).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::Settle {}.data(),
+    }
+}
+
+/// Build a `flash_loan` instruction lending `amount` to `receiver_program`
+///
+/// The accounts the receiver program needs beyond the ones `flash_loan` passes it should be
+/// appended to the instruction.
+#[must_use]
+pub fn flash_loan_ix(
+    borrower: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_to: &Pubkey,
+    receiver_program: &Pubkey,
+    amount: u64,
+    data: Vec<u8>,
+) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: flash_loan_mastery::accounts::FlashLoan {
+            borrower: *borrower,
+            token_from: pool_vault_address(mint),
+            token_to: *token_to,
+            pool_authority,
+            receiver_program: *receiver_program,
+            flash_loan_signer: find_flash_loan_signer().0,
+            token_program: spl_token::ID,
+            pool_config: find_pool_config(&pool_authority).0,
+            pool_share_mint: *pool_share_mint,
+            pool_stats: find_pool_stats(&pool_authority).0,
+        }
+        .to_account_metas(None),
+        data: flash_loan_mastery::instruction::FlashLoan { amount, data }.data(),
+    }
+}
+
+/// Build a `repay` instruction, optionally paying the referral fee to `referral_token_to`
+#[must_use]
+pub fn repay_ix(
+    repayer: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+    token_from: &Pubkey,
+    amount: u64,
+    referral_token_to: Option<&Pubkey>,
+) -> Instruction {
+    let pool_authority = find_pool_authority(mint).0;
+    let mut accounts = flash_loan_mastery::accounts::Repay {
+        repayer: *repayer,
+        token_from: *token_from,
+        token_to: pool_vault_address(mint),
+        pool_authority,
+        instructions_sysvar: sysvar::instructions::ID,
+        token_program: spl_token::ID,
+        pool_config: find_pool_config(&pool_authority).0,
+        pool_share_mint: *pool_share_mint,
+        pool_stats: find_pool_stats(&pool_authority).0,
+    }
+    .to_account_metas(None);
+    if let Some(referral_token_to) = referral_token_to {
+        accounts.push(AccountMeta::new(*referral_token_to, false));
+    }
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts,
+        data: flash_loan_mastery::instruction::Repay { amount }.data(),
+    }
+}
@@ -0,0 +1,414 @@
+//! Versioned transactions and address lookup tables
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::Hash;
+use anchor_lang::solana_program::instruction::{
+    CompiledInstruction, Instruction, InstructionError,
+};
+use anchor_lang::solana_program::message::v0::{self, MessageAddressTableLookup};
+use anchor_lang::solana_program::message::{MessageHeader, VersionedMessage};
+use anchor_lang::solana_program::sysvar;
+use solana_address_lookup_table_program::instruction::{create_lookup_table, extend_lookup_table};
+use solana_address_lookup_table_program::state::AddressLookupTable;
+
+use crate::builder::short_vec_len;
+use crate::{find_pool_authority, find_pool_config, find_pool_stats, pool_vault_address};
+
+/// The addresses stored in an on-chain address lookup table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressLookupTableAccount {
+    /// The address of the lookup table
+    pub key: Pubkey,
+    /// The addresses stored in the lookup table
+    pub addresses: Vec<Pubkey>,
+}
+
+/// Decode an on-chain address lookup table
+pub fn decode_lookup_table(
+    key: Pubkey,
+    data: &[u8],
+) -> std::result::Result<AddressLookupTableAccount, InstructionError> {
+    let lookup_table = AddressLookupTable::deserialize(data)?;
+    Ok(AddressLookupTableAccount {
+        key,
+        addresses: lookup_table.addresses.to_vec(),
+    })
+}
+
+/// Errors returned when compiling a v0 message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileError {
+    /// The message references more than 256 accounts
+    TooManyAccounts,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyAccounts => write!(f, "the message references too many accounts"),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Default)]
+struct KeyMeta {
+    is_signer: bool,
+    is_writable: bool,
+    is_invoked: bool,
+}
+
+/// Compile `instructions` into a v0 message, loading accounts from `lookup_tables` where possible
+///
+/// Signers and invoked programs are always static accounts, as required by the runtime.
+pub fn compile_v0_message(
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    lookup_tables: &[AddressLookupTableAccount],
+    recent_blockhash: Hash,
+) -> std::result::Result<VersionedMessage, CompileError> {
+    let mut key_meta_map = BTreeMap::<Pubkey, KeyMeta>::new();
+    for ix in instructions {
+        key_meta_map.entry(ix.program_id).or_default().is_invoked = true;
+        for account_meta in &ix.accounts {
+            let meta = key_meta_map.entry(account_meta.pubkey).or_default();
+            meta.is_signer |= account_meta.is_signer;
+            meta.is_writable |= account_meta.is_writable;
+        }
+    }
+    key_meta_map.remove(payer);
+
+    // move whatever we can into the lookup tables
+    let mut address_table_lookups = vec![];
+    let mut loaded_writable_keys = vec![];
+    let mut loaded_readonly_keys = vec![];
+    for lookup_table in lookup_tables {
+        let mut lookup = MessageAddressTableLookup {
+            account_key: lookup_table.key,
+            writable_indexes: vec![],
+            readonly_indexes: vec![],
+        };
+        for (index, address) in lookup_table.addresses.iter().enumerate().take(256) {
+            let loadable = matches!(
+                key_meta_map.get(address),
+                Some(meta) if !meta.is_signer && !meta.is_invoked
+            );
+            if !loadable {
+                continue;
+            }
+            let meta = key_meta_map.remove(address).unwrap();
+            let index = u8::try_from(index).unwrap();
+            if meta.is_writable {
+                lookup.writable_indexes.push(index);
+                loaded_writable_keys.push(*address);
+            } else {
+                lookup.readonly_indexes.push(index);
+                loaded_readonly_keys.push(*address);
+            }
+        }
+        if !lookup.writable_indexes.is_empty() || !lookup.readonly_indexes.is_empty() {
+            address_table_lookups.push(lookup);
+        }
+    }
+
+    // the remaining accounts are static, ordered as the runtime expects
+    let keys_where = |is_signer: bool, is_writable: bool| {
+        key_meta_map
+            .iter()
+            .filter(move |(_, meta)| meta.is_signer == is_signer && meta.is_writable == is_writable)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>()
+    };
+    let writable_signer_keys = keys_where(true, true);
+    let readonly_signer_keys = keys_where(true, false);
+    let writable_non_signer_keys = keys_where(false, true);
+    let readonly_non_signer_keys = keys_where(false, false);
+    let header = MessageHeader {
+        num_required_signatures: u8::try_from(
+            1 + writable_signer_keys.len() + readonly_signer_keys.len(),
+        )
+        .map_err(|_| CompileError::TooManyAccounts)?,
+        num_readonly_signed_accounts: u8::try_from(readonly_signer_keys.len())
+            .map_err(|_| CompileError::TooManyAccounts)?,
+        num_readonly_unsigned_accounts: u8::try_from(readonly_non_signer_keys.len())
+            .map_err(|_| CompileError::TooManyAccounts)?,
+    };
+    let account_keys: Vec<Pubkey> = std::iter::once(*payer)
+        .chain(writable_signer_keys)
+        .chain(readonly_signer_keys)
+        .chain(writable_non_signer_keys)
+        .chain(readonly_non_signer_keys)
+        .collect();
+
+    // static keys come first, then keys loaded as writable, then keys loaded as readonly
+    let key_indexes = account_keys
+        .iter()
+        .chain(&loaded_writable_keys)
+        .chain(&loaded_readonly_keys)
+        .enumerate()
+        .map(|(index, key)| Ok((*key, u8::try_from(index)?)))
+        .collect::<std::result::Result<HashMap<Pubkey, u8>, std::num::TryFromIntError>>()
+        .map_err(|_| CompileError::TooManyAccounts)?;
+    let instructions = instructions
+        .iter()
+        .map(|ix| CompiledInstruction {
+            program_id_index: key_indexes[&ix.program_id],
+            accounts: ix
+                .accounts
+                .iter()
+                .map(|account_meta| key_indexes[&account_meta.pubkey])
+                .collect(),
+            data: ix.data.clone(),
+        })
+        .collect();
+
+    Ok(VersionedMessage::V0(v0::Message {
+        header,
+        account_keys,
+        recent_blockhash,
+        instructions,
+        address_table_lookups,
+    }))
+}
+
+/// Get the size of a transaction containing `message` once signed
+#[must_use]
+pub fn versioned_transaction_size(message: &VersionedMessage) -> usize {
+    let num_signatures = usize::from(message.header().num_required_signatures);
+    short_vec_len(num_signatures) + num_signatures * 64 + message.serialize().len()
+}
+
+/// Get the static accounts of the pool lending `mint`, these are the same for every flash loan
+#[must_use]
+pub fn pool_lookup_table_addresses(mint: &Pubkey, pool_share_mint: &Pubkey) -> Vec<Pubkey> {
+    let pool_authority = find_pool_authority(mint).0;
+    vec![
+        pool_authority,
+        pool_vault_address(mint),
+        *pool_share_mint,
+        find_pool_config(&pool_authority).0,
+        find_pool_stats(&pool_authority).0,
+        sysvar::instructions::ID,
+        spl_token::ID,
+    ]
+}
+
+/// Build the instructions creating an address lookup table holding the static accounts of a pool
+///
+/// `recent_slot` must be a recent slot, it is used to derive the lookup table address which is
+/// returned alongside the instructions.
+#[must_use]
+pub fn create_pool_lookup_table_ixs(
+    authority: &Pubkey,
+    payer: &Pubkey,
+    recent_slot: u64,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+) -> (Vec<Instruction>, Pubkey) {
+    let (create_ix, lookup_table) = create_lookup_table(*authority, *payer, recent_slot);
+    let extend_ix =
+        extend_pool_lookup_table_ix(&lookup_table, authority, payer, mint, pool_share_mint);
+    (vec![create_ix, extend_ix], lookup_table)
+}
+
+/// Build the instruction adding the static accounts of a pool to an existing lookup table
+#[must_use]
+pub fn extend_pool_lookup_table_ix(
+    lookup_table: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    mint: &Pubkey,
+    pool_share_mint: &Pubkey,
+) -> Instruction {
+    extend_lookup_table(
+        *lookup_table,
+        *authority,
+        Some(*payer),
+        pool_lookup_table_addresses(mint, pool_share_mint),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_lang::solana_program::instruction::AccountMeta;
+
+    use super::*;
+
+    fn compile(
+        payer: &Pubkey,
+        instructions: &[Instruction],
+        lookup_tables: &[AddressLookupTableAccount],
+    ) -> v0::Message {
+        match compile_v0_message(payer, instructions, lookup_tables, Hash::default()).unwrap() {
+            VersionedMessage::V0(message) => message,
+            VersionedMessage::Legacy(_) => panic!("expected a v0 message"),
+        }
+    }
+
+    fn index_of(message: &v0::Message, key: &Pubkey) -> u8 {
+        let index = message.account_keys.iter().position(|k| k == key).unwrap();
+        u8::try_from(index).unwrap()
+    }
+
+    #[test]
+    fn test_static_account_order() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let writable_signer = Pubkey::new_unique();
+        let readonly_signer = Pubkey::new_unique();
+        let writable = Pubkey::new_unique();
+        let readonly = Pubkey::new_unique();
+        let ix = Instruction {
+            program_id,
+            accounts: vec![
+                AccountMeta::new_readonly(readonly, false),
+                AccountMeta::new(writable, false),
+                AccountMeta::new_readonly(readonly_signer, true),
+                AccountMeta::new(writable_signer, true),
+            ],
+            data: vec![1, 2, 3],
+        };
+        let message = compile(&payer, &[ix], &[]);
+
+        // the payer first, then signers before non-signers and writable before readonly
+        assert_eq!(
+            message.account_keys[..4],
+            [payer, writable_signer, readonly_signer, writable]
+        );
+        let mut readonly_keys = message.account_keys[4..].to_vec();
+        readonly_keys.sort();
+        let mut expected = vec![program_id, readonly];
+        expected.sort();
+        assert_eq!(readonly_keys, expected);
+        assert_eq!(
+            message.header,
+            MessageHeader {
+                num_required_signatures: 3,
+                num_readonly_signed_accounts: 1,
+                num_readonly_unsigned_accounts: 2,
+            }
+        );
+        assert_eq!(
+            message.instructions,
+            vec![CompiledInstruction {
+                program_id_index: index_of(&message, &program_id),
+                accounts: vec![index_of(&message, &readonly), 3, 2, 1],
+                data: vec![1, 2, 3],
+            }]
+        );
+        assert!(message.address_table_lookups.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let account = Pubkey::new_unique();
+        let signer = Pubkey::new_unique();
+        let instructions = [
+            Instruction::new_with_bytes(
+                program_id,
+                &[],
+                vec![
+                    AccountMeta::new_readonly(account, false),
+                    AccountMeta::new_readonly(signer, false),
+                    AccountMeta::new_readonly(payer, false),
+                ],
+            ),
+            Instruction::new_with_bytes(
+                program_id,
+                &[],
+                vec![
+                    AccountMeta::new(account, false),
+                    AccountMeta::new_readonly(signer, true),
+                    AccountMeta::new_readonly(account, false),
+                ],
+            ),
+        ];
+        let message = compile(&payer, &instructions, &[]);
+
+        // every key is listed once with the most permissive of its metas
+        assert_eq!(
+            message.account_keys,
+            vec![payer, signer, account, program_id]
+        );
+        assert_eq!(
+            message.header,
+            MessageHeader {
+                num_required_signatures: 2,
+                num_readonly_signed_accounts: 1,
+                num_readonly_unsigned_accounts: 1,
+            }
+        );
+        assert_eq!(message.instructions[0].accounts, vec![2, 1, 0]);
+        assert_eq!(message.instructions[1].accounts, vec![2, 1, 2]);
+        assert_eq!(message.instructions[1].program_id_index, 3);
+    }
+
+    #[test]
+    fn test_lookup_tables() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let signer = Pubkey::new_unique();
+        let writable = Pubkey::new_unique();
+        let readonly = Pubkey::new_unique();
+        let unlisted = Pubkey::new_unique();
+        let ix = Instruction::new_with_bytes(
+            program_id,
+            &[],
+            vec![
+                AccountMeta::new(signer, true),
+                AccountMeta::new(writable, false),
+                AccountMeta::new_readonly(readonly, false),
+                AccountMeta::new_readonly(unlisted, false),
+            ],
+        );
+        let lookup_table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![
+                program_id,
+                signer,
+                readonly,
+                Pubkey::new_unique(),
+                writable,
+                payer,
+            ],
+        };
+        // a key in two tables is loaded from the first
+        let other_lookup_table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![writable],
+        };
+        let message = compile(&payer, &[ix], &[lookup_table.clone(), other_lookup_table]);
+
+        // the payer, signers and invoked programs stay static
+        assert_eq!(message.account_keys[..2], [payer, signer]);
+        let mut readonly_keys = message.account_keys[2..].to_vec();
+        readonly_keys.sort();
+        let mut expected = vec![program_id, unlisted];
+        expected.sort();
+        assert_eq!(readonly_keys, expected);
+        assert_eq!(
+            message.address_table_lookups,
+            vec![MessageAddressTableLookup {
+                account_key: lookup_table.key,
+                writable_indexes: vec![4],
+                readonly_indexes: vec![2],
+            }]
+        );
+        // loaded writable keys come after the static keys, then loaded readonly keys
+        assert_eq!(
+            message.instructions[0].accounts,
+            vec![1, 4, 5, index_of(&message, &unlisted)]
+        );
+        assert_eq!(
+            message.instructions[0].program_id_index,
+            index_of(&message, &program_id)
+        );
+        message.sanitize(true).unwrap();
+    }
+}
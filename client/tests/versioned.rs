@@ -0,0 +1,285 @@
+//! Flash loans sent as v0 transactions with address lookup tables
+//!
+//! These run on a `solana_runtime` bank rather than a `solana-program-test` one: the
+//! `BanksClient` of `solana-program-test` 1.10 only takes legacy `Transaction`s, so it can't
+//! send the v0 transactions under test. The programs are registered as builtins wrapped with the
+//! test harness `processor!`, the same way `ProgramTest` runs them in the program tests.
+
+use std::sync::Arc;
+
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::message::VersionedMessage;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::pubkey::Pubkey;
+use anchor_lang::solana_program::rent::Rent;
+use anchor_lang::solana_program::{account_info::AccountInfo, system_instruction};
+use flash_loan_mastery::{math, LOAN_FEE, REFERRAL_FEE};
+use flash_loan_mastery_client::builder::PACKET_DATA_SIZE;
+use flash_loan_mastery_client::versioned::decode_lookup_table;
+use flash_loan_mastery_client::{
+    create_pool_lookup_table_ixs, find_pool_authority, init_pool_config_ix, init_pool_ix,
+    init_pool_stats_ix, pool_lookup_table_addresses, pool_vault_address,
+    versioned_transaction_size, AddressLookupTableAccount, FlashLoanBuilder,
+};
+use flash_loan_mastery_test_harness::processor;
+use solana_address_lookup_table_program::instruction::{create_lookup_table, extend_lookup_table};
+use solana_runtime::bank::Bank;
+use solana_runtime::genesis_utils::{create_genesis_config, GenesisConfigInfo};
+use solana_sdk::account::ReadableAccount;
+use solana_sdk::native_token::sol_to_lamports;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::{Transaction, VersionedTransaction};
+use spl_associated_token_account::get_associated_token_address;
+use spl_associated_token_account::instruction::create_associated_token_account;
+
+/// A stand-in for a swap program, it accepts any accounts and does nothing
+fn route_program_id() -> Pubkey {
+    Pubkey::new_from_array([9; 32])
+}
+
+fn process_route_instruction(
+    _program_id: &Pubkey,
+    _accounts: &[AccountInfo],
+    _data: &[u8],
+) -> ProgramResult {
+    Ok(())
+}
+
+/// A stand-in for a swap route touching many accounts
+fn route_ix(accounts: &[Pubkey]) -> Instruction {
+    Instruction {
+        program_id: route_program_id(),
+        accounts: accounts
+            .iter()
+            .map(|account| AccountMeta::new(*account, false))
+            .collect(),
+        data: vec![0; 32],
+    }
+}
+
+/// A bank running the flash loan and SPL programs natively
+fn start() -> (Arc<Bank>, Keypair) {
+    let GenesisConfigInfo {
+        mut genesis_config,
+        mint_keypair,
+        ..
+    } = create_genesis_config(sol_to_lamports(1_000_000.0));
+    // accounts of the test genesis are free, the program pays rent with zero lamports
+    genesis_config.rent = Rent::default();
+    let mut bank = Bank::new_for_tests(&genesis_config);
+    bank.add_builtin(
+        "flash_loan_mastery",
+        &flash_loan_mastery::ID,
+        processor!(flash_loan_mastery::entry),
+    );
+    bank.add_builtin(
+        "spl_token",
+        &spl_token::ID,
+        processor!(spl_token::processor::Processor::process),
+    );
+    bank.add_builtin(
+        "spl_associated_token_account",
+        &spl_associated_token_account::ID,
+        processor!(spl_associated_token_account::processor::process_instruction),
+    );
+    bank.add_builtin(
+        "route",
+        &route_program_id(),
+        processor!(process_route_instruction),
+    );
+    (Arc::new(bank), mint_keypair)
+}
+
+/// Freeze `bank` and start the next slot, lookup tables can't be used in the slot they were
+/// extended in
+fn next_slot(bank: &Arc<Bank>) -> Arc<Bank> {
+    Arc::new(Bank::new_from_parent(
+        bank,
+        &Pubkey::default(),
+        bank.slot() + 1,
+    ))
+}
+
+fn process(bank: &Bank, payer: &Keypair, instructions: &[Instruction], signers: &[&Keypair]) {
+    let mut all_signers = vec![payer];
+    all_signers.extend(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&payer.pubkey()),
+        &all_signers,
+        bank.last_blockhash(),
+    );
+    bank.process_transaction(&transaction).unwrap();
+}
+
+fn create_mint(bank: &Bank, payer: &Keypair) -> Pubkey {
+    let mint = Keypair::new();
+    process(
+        bank,
+        payer,
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                bank.get_minimum_balance_for_rent_exemption(spl_token::state::Mint::LEN),
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::ID,
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::ID,
+                &mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                9,
+            )
+            .unwrap(),
+        ],
+        &[&mint],
+    );
+    mint.pubkey()
+}
+
+fn mint_to(bank: &Bank, payer: &Keypair, mint: &Pubkey, to: &Pubkey, amount: u64) {
+    let ix =
+        spl_token::instruction::mint_to(&spl_token::ID, mint, to, &payer.pubkey(), &[], amount)
+            .unwrap();
+    process(bank, payer, &[ix], &[]);
+}
+
+fn token_balance(bank: &Bank, token_account: &Pubkey) -> u64 {
+    let account = bank.get_account(token_account).unwrap();
+    spl_token::state::Account::unpack(account.data())
+        .unwrap()
+        .amount
+}
+
+fn create_lookup_table_account(
+    bank: &Bank,
+    payer: &Keypair,
+    instructions: &[Instruction],
+    authority: &Keypair,
+    lookup_table: Pubkey,
+) -> AddressLookupTableAccount {
+    process(bank, payer, instructions, &[authority]);
+    let account = bank.get_account(&lookup_table).unwrap();
+    decode_lookup_table(lookup_table, account.data()).unwrap()
+}
+
+#[test]
+fn test_flash_loan_with_lookup_tables() {
+    let (bank, payer_keypair) = start();
+    let payer = payer_keypair.pubkey();
+
+    // the pool lends `mint` out of its vault
+    let mint = create_mint(&bank, &payer_keypair);
+    let pool_share_mint = create_mint(&bank, &payer_keypair);
+    let vault = pool_vault_address(&mint);
+    let token_account = get_associated_token_address(&payer, &mint);
+    let referrer = Pubkey::new_unique();
+    let referral_token_account = get_associated_token_address(&referrer, &mint);
+    process(
+        &bank,
+        &payer_keypair,
+        &[
+            init_pool_ix(&payer, &mint, &pool_share_mint, &payer, &payer),
+            init_pool_config_ix(&payer, &mint),
+            init_pool_stats_ix(&payer, &mint),
+            create_associated_token_account(&payer, &find_pool_authority(&mint).0, &mint),
+            create_associated_token_account(&payer, &payer, &mint),
+            create_associated_token_account(&payer, &referrer, &mint),
+        ],
+        &[],
+    );
+    mint_to(&bank, &payer_keypair, &mint, &vault, 1_000_000_000);
+    mint_to(&bank, &payer_keypair, &mint, &token_account, 1_000_000);
+
+    // lookup tables need a recent slot
+    let bank = next_slot(&bank);
+
+    // the pool lookup table holds the static accounts of the pool
+    let authority = Keypair::new();
+    let (instructions, lookup_table) =
+        create_pool_lookup_table_ixs(&authority.pubkey(), &payer, 0, &mint, &pool_share_mint);
+    let pool_lookup_table = create_lookup_table_account(
+        &bank,
+        &payer_keypair,
+        &instructions,
+        &authority,
+        lookup_table,
+    );
+    assert_eq!(
+        pool_lookup_table.addresses,
+        pool_lookup_table_addresses(&mint, &pool_share_mint)
+    );
+
+    // the route lookup table holds the accounts used by the wrapped instructions
+    let route_accounts: Vec<Pubkey> = (0..30).map(|_| Pubkey::new_unique()).collect();
+    let authority = Keypair::new();
+    let (create_ix, lookup_table) = create_lookup_table(authority.pubkey(), payer, 0);
+    let extend_ix = extend_lookup_table(
+        lookup_table,
+        authority.pubkey(),
+        Some(payer),
+        route_accounts.clone(),
+    );
+    let route_lookup_table = create_lookup_table_account(
+        &bank,
+        &payer_keypair,
+        &[create_ix, extend_ix],
+        &authority,
+        lookup_table,
+    );
+
+    let amount = 100_000_000;
+    let flash_loan = FlashLoanBuilder::new(mint, amount)
+        .borrower(payer, token_account)
+        .pool_share_mint(pool_share_mint)
+        .referrer(referral_token_account)
+        .wrap(vec![route_ix(&route_accounts)])
+        .build()
+        .unwrap();
+    assert!(!flash_loan.fits_in_packet());
+
+    let bank = next_slot(&bank);
+    let lookup_tables = [pool_lookup_table, route_lookup_table];
+    let message = flash_loan
+        .to_v0_message(&lookup_tables, bank.last_blockhash())
+        .unwrap();
+    assert!(versioned_transaction_size(&message) <= PACKET_DATA_SIZE);
+    message.sanitize(true).unwrap();
+
+    let v0_message = match &message {
+        VersionedMessage::V0(message) => message,
+        VersionedMessage::Legacy(_) => panic!("expected a v0 message"),
+    };
+    assert_eq!(v0_message.address_table_lookups.len(), 2);
+    assert_eq!(
+        v0_message.address_table_lookups[1].writable_indexes,
+        (0..30).collect::<Vec<u8>>()
+    );
+    // signers and invoked programs stay static, pool accounts are loaded
+    assert_eq!(v0_message.account_keys[0], payer);
+    assert!(v0_message.account_keys.contains(&flash_loan_mastery::ID));
+    assert!(!v0_message
+        .account_keys
+        .contains(&find_pool_authority(&mint).0));
+    assert!(!v0_message.account_keys.contains(&vault));
+    assert_eq!(v0_message.instructions.len(), 3);
+
+    // the loan is borrowed and repaid with the fee
+    let transaction = VersionedTransaction::try_new(message, &[&payer_keypair]).unwrap();
+    let signature = transaction.signatures[0];
+    bank.process_entry_transactions(vec![transaction]);
+    assert_eq!(bank.get_signature_status(&signature), Some(Ok(())));
+    let split = math::split_repayment(flash_loan.repay_amount, LOAN_FEE, REFERRAL_FEE).unwrap();
+    assert_eq!(token_balance(&bank, &vault), 1_000_000_000 + split.pool_fee);
+    assert_eq!(
+        token_balance(&bank, &referral_token_account),
+        split.referral_fee
+    );
+    assert_eq!(
+        token_balance(&bank, &token_account),
+        1_000_000 - (flash_loan.repay_amount - amount)
+    );
+}
@@ -0,0 +1,275 @@
+//! Fuzz the repay detection in `borrow` with random instruction lists
+//!
+//! Run with `cargo fuzz run repay_detection` from `programs/flash-loan-mastery`.
+#![no_main]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::sysvar;
+use anchor_lang::solana_program::sysvar::instructions::{
+    construct_instructions_data, store_current_index, BorrowedAccountMeta, BorrowedInstruction,
+};
+use arbitrary::Arbitrary;
+use flash_loan_mastery::introspection::check_repayment;
+use flash_loan_mastery::math::repayment_amount;
+use flash_loan_mastery::{
+    get_instruction_discriminator, MAX_LOAN_FEE_MILLIONTHS, MAX_REFERRAL_FEE_MILLIONTHS, MIN_LOAN_FEE_MILLIONTHS,
+};
+use libfuzzer_sys::fuzz_target;
+
+const TOKEN_FROM: Pubkey = Pubkey::new_from_array([1; 32]);
+const POOL_AUTHORITY: Pubkey = Pubkey::new_from_array([2; 32]);
+
+#[derive(Debug, Arbitrary)]
+enum FuzzProgram {
+    FlashLoanMastery,
+    Other(u8),
+}
+
+#[derive(Debug, Arbitrary)]
+enum FuzzKey {
+    TokenFrom,
+    PoolAuthority,
+    Other(u8),
+}
+
+#[derive(Debug, Arbitrary)]
+enum FuzzData {
+    Borrow(Vec<u8>),
+    BorrowAmount(u64),
+    Tranche(Vec<u8>),
+    TrancheAmount(u64),
+    Repay(Vec<u8>),
+    RepayAmount(u64),
+    Settle(Vec<u8>),
+    Receipt(Vec<u8>),
+    ReceiptAmount(u64),
+    FlashLoan(Vec<u8>),
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug, Arbitrary)]
+struct FuzzInstruction {
+    program: FuzzProgram,
+    accounts: Vec<FuzzKey>,
+    data: FuzzData,
+}
+
+#[derive(Debug, Arbitrary)]
+struct FuzzInput {
+    instructions: Vec<FuzzInstruction>,
+    current_idx: u8,
+    amount: u64,
+    loan_fee: u64,
+    referral_fee: u64,
+}
+
+fn discriminator(name: &[u8]) -> [u8; 8] {
+    get_instruction_discriminator(&[name]).to_be_bytes()
+}
+
+impl FuzzInstruction {
+    fn to_instruction(&self) -> Instruction {
+        let program_id = match self.program {
+            FuzzProgram::FlashLoanMastery => flash_loan_mastery::ID,
+            FuzzProgram::Other(seed) => Pubkey::new_from_array([seed; 32]),
+        };
+        let accounts = self
+            .accounts
+            .iter()
+            .map(|key| match key {
+                FuzzKey::TokenFrom => AccountMeta::new(TOKEN_FROM, false),
+                FuzzKey::PoolAuthority => AccountMeta::new(POOL_AUTHORITY, false),
+                FuzzKey::Other(seed) => {
+                    AccountMeta::new(Pubkey::new_from_array([*seed; 32]), false)
+                }
+            })
+            .collect();
+        let data = match &self.data {
+            FuzzData::Borrow(rest) => [&discriminator(b"global:borrow")[..], rest].concat(),
+            FuzzData::BorrowAmount(amount) => {
+                [discriminator(b"global:borrow"), amount.to_le_bytes()].concat()
+            }
+            FuzzData::Tranche(rest) => {
+                [&discriminator(b"global:borrow_tranche")[..], rest].concat()
+            }
+            FuzzData::TrancheAmount(amount) => [
+                discriminator(b"global:borrow_tranche"),
+                amount.to_le_bytes(),
+            ]
+            .concat(),
+            FuzzData::Repay(rest) => [&discriminator(b"global:repay")[..], rest].concat(),
+            FuzzData::RepayAmount(amount) => {
+                [discriminator(b"global:repay"), amount.to_le_bytes()].concat()
+            }
+            FuzzData::Settle(rest) => [&discriminator(b"global:settle")[..], rest].concat(),
+            FuzzData::Receipt(rest) => {
+                [&discriminator(b"global:borrow_with_receipt")[..], rest].concat()
+            }
+            FuzzData::ReceiptAmount(amount) => [
+                discriminator(b"global:borrow_with_receipt"),
+                amount.to_le_bytes(),
+            ]
+            .concat(),
+            FuzzData::FlashLoan(rest) => {
+                [&discriminator(b"global:flash_loan")[..], rest].concat()
+            }
+            FuzzData::Raw(data) => data.clone(),
+        };
+        Instruction {
+            program_id,
+            accounts,
+            data,
+        }
+    }
+}
+
+/// Whether the loan of `amount` at `current_idx` is repaid, this is the specification of
+/// `check_repayment`
+fn is_repaid(
+    instructions: &[Instruction],
+    current_idx: usize,
+    amount: u64,
+    loan_fee: u64,
+    referral_fee: u64,
+) -> bool {
+    let borrow = discriminator(b"global:borrow");
+    let tranche = discriminator(b"global:borrow_tranche");
+    let repay = discriminator(b"global:repay");
+    let settle = discriminator(b"global:settle");
+    let receipt = discriminator(b"global:borrow_with_receipt");
+    let flash_loan = discriminator(b"global:flash_loan");
+    let Ok(mut expected_repayment) = repayment_amount(amount, loan_fee, referral_fee) else {
+        return false;
+    };
+    for ixn in instructions
+        .iter()
+        .skip(current_idx + 1)
+        .filter(|ixn| ixn.program_id == flash_loan_mastery::ID)
+    {
+        let tag = ixn.data.get(..8);
+        // settles are skipped unless they are malformed
+        if tag == Some(&settle[..]) {
+            if ixn.accounts.len() < 4 {
+                return false;
+            }
+            continue;
+        }
+        // flash loans are skipped unless they are malformed
+        if tag == Some(&flash_loan[..]) {
+            let data_len = ixn
+                .data
+                .get(16..20)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            if data_len.map_or(true, |len| ixn.data.len() < 20 + len) || ixn.accounts.len() < 4 {
+                return false;
+            }
+            continue;
+        }
+        if tag != Some(&borrow[..])
+            && tag != Some(&tranche[..])
+            && tag != Some(&receipt[..])
+            && tag != Some(&repay[..])
+        {
+            continue;
+        }
+        // malformed loan instructions are rejected
+        if ixn.data.len() < 16 || ixn.accounts.len() < 4 {
+            return false;
+        }
+        let ix_amount = u64::from_le_bytes(ixn.data[8..16].try_into().unwrap());
+        let pool_authority = ixn.accounts[3].pubkey;
+        if tag == Some(&repay[..]) {
+            let token_to = ixn.accounts[2].pubkey;
+            // repays to other pools are skipped
+            if token_to == TOKEN_FROM || pool_authority == POOL_AUTHORITY {
+                return token_to == TOKEN_FROM
+                    && pool_authority == POOL_AUTHORITY
+                    && ix_amount >= expected_repayment;
+            }
+        } else if ixn.accounts[1].pubkey == TOKEN_FROM || pool_authority == POOL_AUTHORITY {
+            // borrows and receipt loans from the same pool are rejected, tranches are added to
+            // the loan
+            if tag == Some(&borrow[..]) || tag == Some(&receipt[..]) {
+                return false;
+            }
+            match repayment_amount(ix_amount, loan_fee, referral_fee)
+                .ok()
+                .and_then(|repayment| expected_repayment.checked_add(repayment))
+            {
+                Some(total) => expected_repayment = total,
+                None => return false,
+            }
+        }
+    }
+    false
+}
+
+fuzz_target!(|input: FuzzInput| {
+    if input.instructions.is_empty() {
+        return;
+    }
+    let instructions: Vec<Instruction> = input
+        .instructions
+        .iter()
+        .map(FuzzInstruction::to_instruction)
+        .collect();
+    let current_idx = usize::from(input.current_idx) % instructions.len();
+    let loan_fee = MIN_LOAN_FEE_MILLIONTHS
+        + input.loan_fee % (MAX_LOAN_FEE_MILLIONTHS - MIN_LOAN_FEE_MILLIONTHS + 1);
+    let referral_fee = input.referral_fee % (MAX_REFERRAL_FEE_MILLIONTHS + 1);
+
+    // serialize the instructions the same way the runtime does
+    let borrowed: Vec<BorrowedInstruction> = instructions
+        .iter()
+        .map(|ixn| BorrowedInstruction {
+            program_id: &ixn.program_id,
+            accounts: ixn
+                .accounts
+                .iter()
+                .map(|meta| BorrowedAccountMeta {
+                    pubkey: &meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: &ixn.data,
+        })
+        .collect();
+    let mut data = construct_instructions_data(&borrowed);
+    store_current_index(&mut data, u16::try_from(current_idx).unwrap());
+
+    let key = sysvar::instructions::ID;
+    let owner = sysvar::ID;
+    let mut lamports = 0;
+    let instructions_sysvar = AccountInfo::new(
+        &key,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &owner,
+        false,
+        0,
+    );
+
+    let result = check_repayment(
+        &instructions_sysvar,
+        current_idx,
+        &TOKEN_FROM,
+        &POOL_AUTHORITY,
+        input.amount,
+        loan_fee,
+        referral_fee,
+    );
+    assert_eq!(
+        result.is_ok(),
+        is_repaid(
+            &instructions,
+            current_idx,
+            input.amount,
+            loan_fee,
+            referral_fee
+        )
+    );
+});
@@ -0,0 +1,533 @@
+//! Matching a `borrow` with its `repay` and checking who called them using the instructions sysvar
+
+use crate::{get_instruction_discriminator, math, CpiAllowlist, FlashLoanError};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{
+    get_stack_height, Instruction, TRANSACTION_LEVEL_STACK_HEIGHT,
+};
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+
+/// The position of `token_from` in the accounts of `borrow`, `borrow_with_receipt` and
+/// `flash_loan`
+pub const BORROW_TOKEN_FROM_INDEX: usize = 1;
+/// The position of `pool_authority` in the accounts of `borrow`, `borrow_with_receipt` and
+/// `flash_loan`
+pub const BORROW_POOL_AUTHORITY_INDEX: usize = 3;
+/// The position of `token_to` in the accounts of `repay`
+pub const REPAY_TOKEN_TO_INDEX: usize = 2;
+/// The position of `pool_authority` in the accounts of `repay`
+pub const REPAY_POOL_AUTHORITY_INDEX: usize = 3;
+/// The position of `pool_authority` in the accounts of `settle`
+pub const SETTLE_POOL_AUTHORITY_INDEX: usize = 3;
+
+/// An instruction of this program that is part of a flash loan
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoanInstruction {
+    /// A `borrow` instruction
+    Borrow(BorrowInstruction),
+    /// A `borrow_tranche` instruction
+    BorrowTranche(BorrowInstruction),
+    /// A `borrow_with_receipt` instruction
+    BorrowWithReceipt(BorrowInstruction),
+    /// A `flash_loan` instruction
+    FlashLoan(BorrowInstruction),
+    /// A `repay` instruction
+    Repay(RepayInstruction),
+    /// A `settle` instruction
+    Settle(SettleInstruction),
+}
+
+/// A decoded `borrow`, `borrow_tranche`, `borrow_with_receipt` or `flash_loan` instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BorrowInstruction {
+    /// The pool token account being borrowed from
+    pub token_from: Pubkey,
+    /// The pool authority of the pool being borrowed from
+    pub pool_authority: Pubkey,
+    /// The amount borrowed
+    pub amount: u64,
+}
+
+impl BorrowInstruction {
+    /// Whether this borrows from the pool with vault `token_from` or authority `pool_authority`
+    #[must_use]
+    pub fn is_from(&self, token_from: &Pubkey, pool_authority: &Pubkey) -> bool {
+        self.token_from == *token_from || self.pool_authority == *pool_authority
+    }
+}
+
+/// A decoded `repay` instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepayInstruction {
+    /// The pool token account being repaid
+    pub token_to: Pubkey,
+    /// The pool authority of the pool being repaid
+    pub pool_authority: Pubkey,
+    /// The amount repaid
+    pub amount: u64,
+}
+
+impl RepayInstruction {
+    /// Whether this repays the pool with vault `token_to` or authority `pool_authority`
+    #[must_use]
+    pub fn is_to(&self, token_to: &Pubkey, pool_authority: &Pubkey) -> bool {
+        self.token_to == *token_to || self.pool_authority == *pool_authority
+    }
+}
+
+/// A decoded `settle` instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SettleInstruction {
+    /// The pool authority of the pool the loan is settled with
+    pub pool_authority: Pubkey,
+}
+
+/// Parse `ixn` if it is a `borrow`, `borrow_tranche`, `borrow_with_receipt`, `flash_loan`,
+/// `repay` or `settle` instruction of this program
+///
+/// Returns `None` for any other instruction, and an error for a loan instruction that can't be
+/// decoded.
+pub fn parse_loan_instruction(ixn: &Instruction) -> Result<Option<LoanInstruction>> {
+    if ixn.program_id != crate::ID || ixn.data.len() < 8 {
+        return Ok(None);
+    }
+    let (tag, mut args) = ixn.data.split_at(8);
+    if tag == get_instruction_discriminator(&[b"global:borrow"]).to_be_bytes() {
+        let borrow = crate::instruction::Borrow::deserialize(&mut args)
+            .map_err(|_| error!(FlashLoanError::MalformedBorrowInstruction))?;
+        return parse_borrow(ixn, borrow.amount)
+            .map(|borrow| Some(LoanInstruction::Borrow(borrow)));
+    }
+    if tag == get_instruction_discriminator(&[b"global:borrow_tranche"]).to_be_bytes() {
+        let borrow = crate::instruction::BorrowTranche::deserialize(&mut args)
+            .map_err(|_| error!(FlashLoanError::MalformedBorrowInstruction))?;
+        return parse_borrow(ixn, borrow.amount)
+            .map(|borrow| Some(LoanInstruction::BorrowTranche(borrow)));
+    }
+    if tag == get_instruction_discriminator(&[b"global:borrow_with_receipt"]).to_be_bytes() {
+        let borrow = crate::instruction::BorrowWithReceipt::deserialize(&mut args)
+            .map_err(|_| error!(FlashLoanError::MalformedBorrowInstruction))?;
+        return parse_borrow(ixn, borrow.amount)
+            .map(|borrow| Some(LoanInstruction::BorrowWithReceipt(borrow)));
+    }
+    if tag == get_instruction_discriminator(&[b"global:flash_loan"]).to_be_bytes() {
+        let flash_loan = crate::instruction::FlashLoan::deserialize(&mut args)
+            .map_err(|_| error!(FlashLoanError::MalformedBorrowInstruction))?;
+        return parse_borrow(ixn, flash_loan.amount)
+            .map(|borrow| Some(LoanInstruction::FlashLoan(borrow)));
+    }
+    if tag == get_instruction_discriminator(&[b"global:settle"]).to_be_bytes() {
+        return match ixn.accounts.get(SETTLE_POOL_AUTHORITY_INDEX) {
+            Some(pool_authority) => Ok(Some(LoanInstruction::Settle(SettleInstruction {
+                pool_authority: pool_authority.pubkey,
+            }))),
+            None => err!(FlashLoanError::MalformedSettleInstruction),
+        };
+    }
+    if tag != get_instruction_discriminator(&[b"global:repay"]).to_be_bytes() {
+        return Ok(None);
+    }
+
+    let repay = crate::instruction::Repay::deserialize(&mut args)
+        .map_err(|_| error!(FlashLoanError::MalformedRepayInstruction))?;
+    match (
+        ixn.accounts.get(REPAY_TOKEN_TO_INDEX),
+        ixn.accounts.get(REPAY_POOL_AUTHORITY_INDEX),
+    ) {
+        (Some(token_to), Some(pool_authority)) => {
+            Ok(Some(LoanInstruction::Repay(RepayInstruction {
+                token_to: token_to.pubkey,
+                pool_authority: pool_authority.pubkey,
+                amount: repay.amount,
+            })))
+        }
+        _ => err!(FlashLoanError::MalformedRepayInstruction),
+    }
+}
+
+/// Get the pool accounts of a `borrow`, `borrow_tranche`, `borrow_with_receipt` or `flash_loan`
+/// instruction
+fn parse_borrow(ixn: &Instruction, amount: u64) -> Result<BorrowInstruction> {
+    match (
+        ixn.accounts.get(BORROW_TOKEN_FROM_INDEX),
+        ixn.accounts.get(BORROW_POOL_AUTHORITY_INDEX),
+    ) {
+        (Some(token_from), Some(pool_authority)) => Ok(BorrowInstruction {
+            token_from: token_from.pubkey,
+            pool_authority: pool_authority.pubkey,
+            amount,
+        }),
+        _ => err!(FlashLoanError::MalformedBorrowInstruction),
+    }
+}
+
+/// Get the index of the current instruction, checking that it is an instruction of this program
+/// or a CPI from a program on the allowlist of `pool_authority`
+///
+/// The allowlist is looked up in `accounts`. Allowlisted programs may only call this program from
+/// an instruction at the top of the transaction, not through another CPI. When the instruction at
+/// the top of the transaction is one of this program, this must be it and not a CPI made while
+/// it runs, such as one from the receiver of a `flash_loan`.
+pub fn check_caller(
+    instructions_sysvar: &AccountInfo,
+    pool_authority: &Pubkey,
+    accounts: &[AccountInfo],
+) -> Result<usize> {
+    let current_idx = load_current_index_checked(instructions_sysvar)? as usize;
+    let current_ixn = load_instruction_at_checked(current_idx, instructions_sysvar)?;
+    if current_ixn.program_id == crate::ID {
+        require_eq!(
+            get_stack_height(),
+            TRANSACTION_LEVEL_STACK_HEIGHT,
+            FlashLoanError::ProgramMismatch
+        );
+        return Ok(current_idx);
+    }
+
+    require_eq!(
+        get_stack_height(),
+        TRANSACTION_LEVEL_STACK_HEIGHT + 1,
+        FlashLoanError::ProgramMismatch
+    );
+    let allowed = accounts
+        .iter()
+        .filter_map(|account| AccountLoader::<CpiAllowlist>::try_from(account).ok())
+        .any(|cpi_allowlist| {
+            cpi_allowlist.load().is_ok_and(|cpi_allowlist| {
+                cpi_allowlist.pool_authority == *pool_authority
+                    && cpi_allowlist.contains(&current_ixn.program_id)
+            })
+        });
+    require!(allowed, FlashLoanError::ProgramMismatch);
+
+    Ok(current_idx)
+}
+
+/// Check that the loan of `amount` at `current_idx` is repaid by a later repay to `token_from`
+///
+/// Tranches borrowed from the same pool before the repay are added to the loan, and the repay must
+/// cover all of them and their fees. Any other borrow from the same pool before the repay is an
+/// error, as is a `borrow_with_receipt` whose settle would count the fees of this loan. Loans from
+/// other pools may be nested in or interleaved with this one.
+pub fn check_repayment(
+    instructions_sysvar: &AccountInfo,
+    current_idx: usize,
+    token_from: &Pubkey,
+    pool_authority: &Pubkey,
+    amount: u64,
+    loan_fee: u64,
+    referral_fee: u64,
+) -> Result<()> {
+    let mut expected_repayment = math::repayment_amount(amount, loan_fee, referral_fee)?;
+    let mut ix_index = current_idx;
+    loop {
+        ix_index += 1;
+        let ixn = load_instruction_at_checked(ix_index, instructions_sysvar)
+            .map_err(|_| error!(FlashLoanError::NoRepaymentInstructionFound))?;
+        match parse_loan_instruction(&ixn)? {
+            Some(LoanInstruction::Repay(repay)) if repay.is_to(token_from, pool_authority) => {
+                require_keys_eq!(repay.token_to, *token_from, FlashLoanError::AddressMismatch);
+                require_keys_eq!(
+                    repay.pool_authority,
+                    *pool_authority,
+                    FlashLoanError::PoolMismatch
+                );
+                require_gte!(
+                    repay.amount,
+                    expected_repayment,
+                    FlashLoanError::IncorrectRepaymentAmount
+                );
+                // ALL is good :)
+                return Ok(());
+            }
+            Some(LoanInstruction::Borrow(borrow) | LoanInstruction::BorrowWithReceipt(borrow))
+                if borrow.is_from(token_from, pool_authority) =>
+            {
+                return err!(FlashLoanError::CannotBorrowBeforeRepay);
+            }
+            Some(LoanInstruction::BorrowTranche(tranche))
+                if tranche.is_from(token_from, pool_authority) =>
+            {
+                expected_repayment = expected_repayment
+                    .checked_add(math::repayment_amount(
+                        tranche.amount,
+                        loan_fee,
+                        referral_fee,
+                    )?)
+                    .ok_or(FlashLoanError::MathOverflow)?;
+            }
+            // instructions for loans from other pools
+            _ => {}
+        }
+    }
+}
+
+/// Check that the loan at `current_idx` is followed by a settle of the pool `pool_authority`
+///
+/// The settle checks the vault balance itself, so the funds may be returned by any route, but
+/// any fee paid into the vault before the settle counts towards the receipt. Any other loan or
+/// repay of the same pool before the settle is therefore an error.
+pub fn check_settlement(
+    instructions_sysvar: &AccountInfo,
+    current_idx: usize,
+    token_from: &Pubkey,
+    pool_authority: &Pubkey,
+) -> Result<()> {
+    let mut ix_index = current_idx;
+    loop {
+        ix_index += 1;
+        let ixn = load_instruction_at_checked(ix_index, instructions_sysvar)
+            .map_err(|_| error!(FlashLoanError::NoRepaymentInstructionFound))?;
+        match parse_loan_instruction(&ixn)? {
+            Some(LoanInstruction::Settle(settle)) if settle.pool_authority == *pool_authority => {
+                return Ok(());
+            }
+            Some(
+                LoanInstruction::Borrow(borrow)
+                | LoanInstruction::BorrowTranche(borrow)
+                | LoanInstruction::BorrowWithReceipt(borrow)
+                | LoanInstruction::FlashLoan(borrow),
+            ) if borrow.is_from(token_from, pool_authority) => {
+                return err!(FlashLoanError::LoanOutstanding);
+            }
+            Some(LoanInstruction::Repay(repay)) if repay.is_to(token_from, pool_authority) => {
+                return err!(FlashLoanError::LoanOutstanding);
+            }
+            // instructions for loans from other pools
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::InstructionData;
+
+    fn borrow_ix(amount: u64) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: crate::accounts::Borrow {
+                borrower: Pubkey::new_unique(),
+                token_from: Pubkey::new_from_array([1; 32]),
+                token_to: Pubkey::new_unique(),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                instructions_sysvar: Pubkey::new_unique(),
+                token_program: Pubkey::new_unique(),
+                pool_config: Pubkey::new_unique(),
+                pool_stats: Pubkey::new_unique(),
+            }
+            .to_account_metas(None),
+            data: crate::instruction::Borrow { amount }.data(),
+        }
+    }
+
+    fn repay_ix(amount: u64) -> Instruction {
+        Instruction {
+            program_id: crate::ID,
+            accounts: crate::accounts::Repay {
+                repayer: Pubkey::new_unique(),
+                token_from: Pubkey::new_unique(),
+                token_to: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                instructions_sysvar: Pubkey::new_unique(),
+                token_program: Pubkey::new_unique(),
+                pool_config: Pubkey::new_unique(),
+                pool_share_mint: Pubkey::new_unique(),
+                pool_stats: Pubkey::new_unique(),
+            }
+            .to_account_metas(None),
+            data: crate::instruction::Repay { amount }.data(),
+        }
+    }
+
+    #[test]
+    fn test_parse_repay() {
+        // this also checks the account indices against the `Repay` accounts
+        assert_eq!(
+            parse_loan_instruction(&repay_ix(1_000)).unwrap(),
+            Some(LoanInstruction::Repay(RepayInstruction {
+                token_to: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                amount: 1_000,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_borrow() {
+        // this also checks the account indices against the `Borrow` accounts
+        assert_eq!(
+            parse_loan_instruction(&borrow_ix(1_000)).unwrap(),
+            Some(LoanInstruction::Borrow(BorrowInstruction {
+                token_from: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                amount: 1_000,
+            }))
+        );
+
+        let mut ixn = borrow_ix(1_000);
+        ixn.data = crate::instruction::BorrowTranche { amount: 1_000 }.data();
+        assert_eq!(
+            parse_loan_instruction(&ixn).unwrap(),
+            Some(LoanInstruction::BorrowTranche(BorrowInstruction {
+                token_from: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                amount: 1_000,
+            }))
+        );
+
+        let mut ixn = borrow_ix(1_000);
+        ixn.data.truncate(15);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedBorrowInstruction.into())
+        );
+
+        let mut ixn = borrow_ix(1_000);
+        ixn.accounts.truncate(BORROW_POOL_AUTHORITY_INDEX);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedBorrowInstruction.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_receipt_and_flash_loan() {
+        // this also checks the account indices against the `BorrowWithReceipt` and `FlashLoan`
+        // accounts
+        let mut ixn = Instruction {
+            program_id: crate::ID,
+            accounts: crate::accounts::BorrowWithReceipt {
+                borrower: Pubkey::new_unique(),
+                token_from: Pubkey::new_from_array([1; 32]),
+                token_to: Pubkey::new_unique(),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                instructions_sysvar: Pubkey::new_unique(),
+                token_program: Pubkey::new_unique(),
+                pool_config: Pubkey::new_unique(),
+                pool_stats: Pubkey::new_unique(),
+                pool_share_mint: Pubkey::new_unique(),
+                loan_receipt: Pubkey::new_unique(),
+                system_program: Pubkey::new_unique(),
+            }
+            .to_account_metas(None),
+            data: crate::instruction::BorrowWithReceipt { amount: 1_000 }.data(),
+        };
+        assert_eq!(
+            parse_loan_instruction(&ixn).unwrap(),
+            Some(LoanInstruction::BorrowWithReceipt(BorrowInstruction {
+                token_from: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                amount: 1_000,
+            }))
+        );
+        ixn.data.truncate(15);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedBorrowInstruction.into())
+        );
+
+        let mut ixn = Instruction {
+            program_id: crate::ID,
+            accounts: crate::accounts::FlashLoan {
+                borrower: Pubkey::new_unique(),
+                token_from: Pubkey::new_from_array([1; 32]),
+                token_to: Pubkey::new_unique(),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                receiver_program: Pubkey::new_unique(),
+                flash_loan_signer: Pubkey::new_unique(),
+                token_program: Pubkey::new_unique(),
+                pool_config: Pubkey::new_unique(),
+                pool_share_mint: Pubkey::new_unique(),
+                pool_stats: Pubkey::new_unique(),
+            }
+            .to_account_metas(None),
+            data: crate::instruction::FlashLoan {
+                amount: 1_000,
+                data: vec![1, 2, 3],
+            }
+            .data(),
+        };
+        assert_eq!(
+            parse_loan_instruction(&ixn).unwrap(),
+            Some(LoanInstruction::FlashLoan(BorrowInstruction {
+                token_from: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                amount: 1_000,
+            }))
+        );
+        ixn.data.pop();
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedBorrowInstruction.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_repay() {
+        let mut ixn = repay_ix(1_000);
+        ixn.data.truncate(15);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedRepayInstruction.into())
+        );
+
+        let mut ixn = repay_ix(1_000);
+        ixn.accounts.truncate(REPAY_POOL_AUTHORITY_INDEX);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedRepayInstruction.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_settle() {
+        // this also checks the account index against the `Settle` accounts
+        let mut ixn = Instruction {
+            program_id: crate::ID,
+            accounts: crate::accounts::Settle {
+                borrower: Pubkey::new_unique(),
+                loan_receipt: Pubkey::new_unique(),
+                token_to: Pubkey::new_from_array([1; 32]),
+                pool_authority: Pubkey::new_from_array([2; 32]),
+                pool_share_mint: Pubkey::new_unique(),
+                pool_stats: Pubkey::new_unique(),
+            }
+            .to_account_metas(None),
+            data: crate::instruction::Settle {}.data(),
+        };
+        assert_eq!(
+            parse_loan_instruction(&ixn).unwrap(),
+            Some(LoanInstruction::Settle(SettleInstruction {
+                pool_authority: Pubkey::new_from_array([2; 32]),
+            }))
+        );
+
+        ixn.accounts.truncate(SETTLE_POOL_AUTHORITY_INDEX);
+        assert_eq!(
+            parse_loan_instruction(&ixn),
+            Err(FlashLoanError::MalformedSettleInstruction.into())
+        );
+    }
+
+    #[test]
+    fn test_parse_other_instructions() {
+        let mut ixn = repay_ix(1_000);
+        ixn.program_id = Pubkey::new_unique();
+        assert_eq!(parse_loan_instruction(&ixn).unwrap(), None);
+
+        let mut ixn = repay_ix(1_000);
+        ixn.data.truncate(7);
+        assert_eq!(parse_loan_instruction(&ixn).unwrap(), None);
+
+        let ixn = Instruction {
+            program_id: crate::ID,
+            accounts: vec![],
+            data: crate::instruction::Deposit { amount: 1_000 }.data(),
+        };
+        assert_eq!(parse_loan_instruction(&ixn).unwrap(), None);
+    }
+}
@@ -9,14 +9,20 @@
 
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::hash::hashv;
+use anchor_lang::solana_program::instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT};
+#[cfg(feature = "event-cpi")]
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke_signed;
 use anchor_lang::solana_program::sysvar;
-use anchor_lang::solana_program::sysvar::instructions::{
-    load_current_index_checked, load_instruction_at_checked,
-};
 use anchor_spl::token::{Mint, Token, TokenAccount};
+pub use flash_loan_receiver::FlashLoanPayload;
+#[cfg(not(feature = "no-entrypoint"))]
 use solana_security_txt::security_txt;
 use spl_associated_token_account::get_associated_token_address;
 
+pub mod introspection;
+pub mod math;
+
 #[cfg(not(feature = "no-entrypoint"))]
 security_txt! {
     name: "Flash Loan Mastery",
@@ -29,13 +35,52 @@ security_txt! {
 
 declare_id!("1oanfPPN8r1i4UbugXHDxWMbWVJ5qLSN5qzNFZkz6Fg");
 
-// with these numbers total loan fee is 0.09% or 0.095% if there is a referral
-pub static LOAN_FEE: u128 = 900;
-pub static REFERRAL_FEE: u128 = 50;
-pub static LOAN_FEE_DENOMINATOR: u128 = 10000;
-pub static ONE_HUNDRED: u128 = 100;
+// fees are expressed in millionths of the borrowed amount, out of `FEE_DENOMINATOR`
+// the default fees new pools start with, the total loan fee is 0.09% or 0.095% if there is a
+// referral
+pub static LOAN_FEE: u64 = 900;
+pub static REFERRAL_FEE: u64 = 50;
+pub static FEE_DENOMINATOR: u128 = 1_000_000;
+
+// bounds in millionths for fees set using `set_pool_config`
+// the loan fee is between 0.01% and 1% and the referral fee is at most 0.5%
+pub static MIN_LOAN_FEE_MILLIONTHS: u64 = 100;
+pub static MAX_LOAN_FEE_MILLIONTHS: u64 = 10_000;
+pub static MIN_REFERRAL_FEE_MILLIONTHS: u64 = 0;
+pub static MAX_REFERRAL_FEE_MILLIONTHS: u64 = 5_000;
+
+// virtual offsets added to the pool share supply and the pool amount in the share math
+// they make inflating the share price of an empty pool by donating to its vault unprofitable:
+// the virtual shares give pool shares 3 more decimals of precision than the token, so a
+// donation costs the attacker about 1000 times what the depositors after them lose to rounding
+pub static VIRTUAL_SHARES: u128 = 1_000;
+pub static VIRTUAL_ASSETS: u128 = 1;
+
+// the number of programs a pool admin can allow to call `borrow` and `repay` through CPI
+pub const MAX_CPI_PROGRAMS: usize = 8;
 
 pub static POOL_SEED: &[u8] = b"flash_loan";
+pub static POOL_CONFIG_SEED: &[u8] = b"pool_config";
+pub static POOL_STATS_SEED: &[u8] = b"pool_stats";
+pub static LOAN_RECEIPT_SEED: &[u8] = b"loan_receipt";
+pub static CPI_ALLOWLIST_SEED: &[u8] = b"cpi_allowlist";
+pub static EVENT_AUTHORITY_SEED: &[u8] = b"__event_authority";
+pub static FLASH_LOAN_SIGNER_SEED: &[u8] = b"flash_loan_signer";
+/// The bump of the event authority PDA, so that emitting events doesn't have to search for it
+pub static EVENT_AUTHORITY_BUMP: u8 = 255;
+
+/// The bump of the flash loan signer PDA, which signs the calls of `flash_loan` to receivers so
+/// they can tell them apart from calls by anyone else. It owns nothing.
+pub static FLASH_LOAN_SIGNER_BUMP: u8 = 254;
+
+/// The instruction tag of self-CPI event instructions, this is the same tag used by Anchor
+pub static EVENT_IX_TAG: u64 = 0x1d9a_cb51_2ea5_45e4;
+
+/// Get the event authority PDA, the signer of self-CPI events
+pub fn event_authority() -> Result<Pubkey> {
+    Pubkey::create_program_address(&[EVENT_AUTHORITY_SEED, &[EVENT_AUTHORITY_BUMP]], &crate::ID)
+        .map_err(|err| ProgramError::from(err).into())
+}
 
 #[must_use]
 /// Get the Anchor instruction identifier
@@ -46,18 +91,87 @@ pub fn get_instruction_discriminator(namespace: &[&[u8]]) -> u64 {
     u64::from_be_bytes(discriminator)
 }
 
+/// Emit an event using a self-CPI whose instruction data is the serialized event
+///
+/// Unlike log based events, these can't be truncated. The event authority and this program must
+/// be included in the remaining accounts of the instruction emitting the event.
+#[cfg(feature = "event-cpi")]
+pub fn emit_event<T: anchor_lang::Event>(event: &T, accounts: &[AccountInfo]) -> Result<()> {
+    let event_authority = event_authority()?;
+    let event_authority_info = accounts
+        .iter()
+        .find(|account| account.key() == event_authority)
+        .ok_or(FlashLoanError::MissingEventAccounts)?;
+    let program_info = accounts
+        .iter()
+        .find(|account| account.key() == crate::ID)
+        .ok_or(FlashLoanError::MissingEventAccounts)?;
+
+    let mut data = EVENT_IX_TAG.to_le_bytes().to_vec();
+    data.extend(event.data());
+    invoke_signed(
+        &Instruction {
+            program_id: crate::ID,
+            accounts: vec![AccountMeta::new_readonly(event_authority, true)],
+            data,
+        },
+        &[event_authority_info.clone(), program_info.clone()],
+        &[&[EVENT_AUTHORITY_SEED, &[EVENT_AUTHORITY_BUMP]]],
+    )?;
+
+    Ok(())
+}
+
+/// Emit an event using the program logs
+#[cfg(not(feature = "event-cpi"))]
+pub fn emit_event<T: anchor_lang::Event>(event: &T, _accounts: &[AccountInfo]) -> Result<()> {
+    anchor_lang::solana_program::log::sol_log_data(&[&event.data()]);
+
+    Ok(())
+}
+
 #[program]
 #[allow(clippy::needless_pass_by_value)]
 pub mod flash_loan_mastery {
     use super::*;
 
+    /// Handle instructions that don't match any of the above
+    ///
+    /// The only such instructions are self-CPI events when the `event-cpi` feature is enabled.
+    pub fn fallback(_program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> Result<()> {
+        if cfg!(feature = "event-cpi") && data.starts_with(&EVENT_IX_TAG.to_le_bytes()) {
+            // make sure the event was emitted by this program
+            let event_authority_info = accounts
+                .first()
+                .ok_or(FlashLoanError::MissingEventAccounts)?;
+            require_keys_eq!(
+                event_authority_info.key(),
+                event_authority()?,
+                FlashLoanError::AddressMismatch
+            );
+            require!(event_authority_info.is_signer, ErrorCode::AccountNotSigner);
+            return Ok(());
+        }
+
+        Err(ErrorCode::InstructionFallbackNotFound.into())
+    }
+
     /// Initialize a lending pool
-    pub fn init_pool(ctx: Context<InitPool>) -> Result<()> {
+    pub fn init_pool(ctx: Context<InitPool>, admin: Pubkey) -> Result<()> {
+        require_keys_neq!(admin, Pubkey::default(), FlashLoanError::AddressMismatch);
+
         let mut pool_authority = ctx.accounts.pool_authority.load_init()?;
         *pool_authority = PoolAuthority {
             mint: ctx.accounts.mint.key(),
             pool_share_mint: ctx.accounts.pool_share_mint.key(),
             bump: *ctx.bumps.get("pool_authority").unwrap(),
+            admin,
+            pending_admin: Pubkey::default(),
+            guardian: Pubkey::default(),
+            paused: 0,
+            flash_loan_in_progress: 0,
+            loan_outstanding: 0,
+            migrated: 0,
         };
 
         anchor_spl::token::set_authority(
@@ -86,83 +200,433 @@ pub mod flash_loan_mastery {
             )?;
         }
 
+        emit_event(
+            &PoolInitialized {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                mint: ctx.accounts.mint.key(),
+                pool_share_mint: ctx.accounts.pool_share_mint.key(),
+                admin,
+            },
+            ctx.remaining_accounts,
+        )?;
+
+        Ok(())
+    }
+
+    /// Migrate a `PoolAuthority` account created before pools had an admin
+    ///
+    /// Anyone can migrate a pool, paying for the additional space, and migrating a pool twice
+    /// does nothing. The pool is left without an admin, which `init_pool_admin` can set.
+    pub fn migrate_pool_authority(ctx: Context<MigratePoolAuthority>) -> Result<()> {
+        let pool_authority_info = ctx.accounts.pool_authority.to_account_info();
+        {
+            let data = pool_authority_info.try_borrow_data()?;
+            require!(
+                data.len() >= 8
+                    && data[..8] == <PoolAuthority as anchor_lang::Discriminator>::discriminator(),
+                ErrorCode::AccountDiscriminatorMismatch
+            );
+            if data.len() == PoolAuthority::LEN {
+                return Ok(());
+            }
+            require_eq!(
+                data.len(),
+                PoolAuthority::LEGACY_LEN,
+                ErrorCode::AccountDidNotDeserialize
+            );
+        }
+
+        // fund the additional space
+        let rent = Rent::get()?;
+        let lamports_needed = rent
+            .minimum_balance(PoolAuthority::LEN)
+            .saturating_sub(pool_authority_info.lamports());
+        if lamports_needed > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.funder.to_account_info(),
+                        to: pool_authority_info.clone(),
+                    },
+                ),
+                lamports_needed,
+            )?;
+        }
+        // the new fields start zeroed: no admin, no guardian, not paused and no loan in progress
+        pool_authority_info.realloc(PoolAuthority::LEN, true)?;
+        // `migrated` is the last field
+        pool_authority_info.try_borrow_mut_data()?[PoolAuthority::LEN - 1] = 1;
+
+        Ok(())
+    }
+
+    /// Set the admin of a migrated lending pool that has none, only the upgrade authority of this
+    /// program can
+    pub fn init_pool_admin(ctx: Context<InitPoolAdmin>, admin: Pubkey) -> Result<()> {
+        require_keys_neq!(admin, Pubkey::default(), FlashLoanError::AddressMismatch);
+
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.admin = admin;
+        pool_authority.pending_admin = Pubkey::default();
+
+        Ok(())
+    }
+
+    /// Propose a new admin for a lending pool
+    pub fn propose_admin(ctx: Context<ProposeAdmin>, new_admin: Pubkey) -> Result<()> {
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.pending_admin = new_admin;
+
+        Ok(())
+    }
+
+    /// Accept the admin role of a lending pool
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.admin = ctx.accounts.pending_admin.key();
+        pool_authority.pending_admin = Pubkey::default();
+
+        Ok(())
+    }
+
+    /// Set the guardian allowed to pause a lending pool
+    pub fn set_guardian(ctx: Context<SetGuardian>, guardian: Pubkey) -> Result<()> {
+        require_keys_neq!(guardian, Pubkey::default(), FlashLoanError::AddressMismatch);
+
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.guardian = guardian;
+
+        Ok(())
+    }
+
+    /// Pause a lending pool, only withdrawals and repayments remain possible
+    pub fn pause(ctx: Context<Pause>) -> Result<()> {
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.paused = 1;
+
+        Ok(())
+    }
+
+    /// Unpause a lending pool
+    pub fn unpause(ctx: Context<Unpause>) -> Result<()> {
+        let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+        pool_authority.paused = 0;
+
+        Ok(())
+    }
+
+    /// Initialize the fee configuration of a lending pool using the default fees
+    pub fn init_pool_config(ctx: Context<InitPoolConfig>) -> Result<()> {
+        let mut pool_config = ctx.accounts.pool_config.load_init()?;
+        *pool_config = PoolConfig {
+            pool_authority: ctx.accounts.pool_authority.key(),
+            loan_fee_millionths: LOAN_FEE,
+            referral_fee_millionths: REFERRAL_FEE,
+            bump: *ctx.bumps.get("pool_config").unwrap(),
+        };
+
+        Ok(())
+    }
+
+    /// Initialize the statistics of a lending pool
+    pub fn init_pool_stats(ctx: Context<InitPoolStats>) -> Result<()> {
+        let mut pool_stats = ctx.accounts.pool_stats.load_init()?;
+        *pool_stats = PoolStats {
+            pool_authority: ctx.accounts.pool_authority.key(),
+            total_loans: 0,
+            total_borrowed: 0,
+            total_fees: 0,
+            total_referral_fees: 0,
+            last_loan_slot: 0,
+            bump: *ctx.bumps.get("pool_stats").unwrap(),
+        };
+
+        Ok(())
+    }
+
+    /// Update the fees charged by a lending pool
+    pub fn set_pool_config(
+        ctx: Context<SetPoolConfig>,
+        loan_fee_millionths: u64,
+        referral_fee_millionths: u64,
+    ) -> Result<()> {
+        require!(
+            (MIN_LOAN_FEE_MILLIONTHS..=MAX_LOAN_FEE_MILLIONTHS).contains(&loan_fee_millionths),
+            FlashLoanError::InvalidFee
+        );
+        require!(
+            (MIN_REFERRAL_FEE_MILLIONTHS..=MAX_REFERRAL_FEE_MILLIONTHS)
+                .contains(&referral_fee_millionths),
+            FlashLoanError::InvalidFee
+        );
+
+        let mut pool_config = ctx.accounts.pool_config.load_mut()?;
+        pool_config.loan_fee_millionths = loan_fee_millionths;
+        pool_config.referral_fee_millionths = referral_fee_millionths;
+
+        Ok(())
+    }
+
+    /// Initialize the list of programs allowed to call `borrow` and `repay` of a lending pool
+    /// through CPI, the list starts empty
+    pub fn init_cpi_allowlist(ctx: Context<InitCpiAllowlist>) -> Result<()> {
+        let mut cpi_allowlist = ctx.accounts.cpi_allowlist.load_init()?;
+        *cpi_allowlist = CpiAllowlist {
+            pool_authority: ctx.accounts.pool_authority.key(),
+            programs: [Pubkey::default(); MAX_CPI_PROGRAMS],
+            bump: *ctx.bumps.get("cpi_allowlist").unwrap(),
+        };
+
+        Ok(())
+    }
+
+    /// Allow `program` to call `borrow` and `repay` of a lending pool through CPI
+    ///
+    /// Only instructions at the top of the transaction are checked for repayment, so a loan
+    /// borrowed by `program` must still be repaid by a later `repay` instruction of the
+    /// transaction. The pool records the loan until that repay and rejects another borrow through
+    /// CPI meanwhile, as the repayment check of the first loan can't see it.
+    pub fn add_cpi_program(ctx: Context<UpdateCpiAllowlist>, program: Pubkey) -> Result<()> {
+        require_keys_neq!(program, Pubkey::default(), FlashLoanError::AddressMismatch);
+
+        let mut cpi_allowlist = ctx.accounts.cpi_allowlist.load_mut()?;
+        if cpi_allowlist.contains(&program) {
+            return Ok(());
+        }
+        let slot = cpi_allowlist
+            .programs
+            .iter_mut()
+            .find(|slot| **slot == Pubkey::default())
+            .ok_or(FlashLoanError::CpiAllowlistFull)?;
+        *slot = program;
+
+        Ok(())
+    }
+
+    /// Stop allowing `program` to call `borrow` and `repay` of a lending pool through CPI
+    pub fn remove_cpi_program(ctx: Context<UpdateCpiAllowlist>, program: Pubkey) -> Result<()> {
+        let mut cpi_allowlist = ctx.accounts.cpi_allowlist.load_mut()?;
+        for slot in &mut cpi_allowlist.programs {
+            if *slot == program {
+                *slot = Pubkey::default();
+            }
+        }
+
         Ok(())
     }
 
     /// Deposit funds into a lending pool
     pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-        // calculate share amount
-        // amount * total shares / total pool amount
-        let share_amount = if ctx.accounts.token_to.amount == 0 {
-            amount
-        } else {
-            u64::try_from(
-                u128::from(amount) * u128::from(ctx.accounts.pool_share_mint.supply)
-                    / u128::from(ctx.accounts.token_to.amount),
-            )
-            .unwrap()
-        };
+        let share_amount = ctx.accounts.share_amount(amount)?;
+        require_gt!(share_amount, 0, FlashLoanError::ZeroSharesMinted);
 
-        // transfer to pool
-        anchor_spl::token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::Transfer {
-                    from: ctx.accounts.token_from.to_account_info(),
-                    to: ctx.accounts.token_to.to_account_info(),
-                    authority: ctx.accounts.depositor.to_account_info(),
-                },
-            ),
+        ctx.accounts
+            .issue(amount, share_amount, ctx.remaining_accounts)
+    }
+
+    /// Deposit funds into a lending pool, failing if fewer than `min_shares` pool shares are minted
+    pub fn deposit_with_min_shares(
+        ctx: Context<Deposit>,
+        amount: u64,
+        min_shares: u64,
+    ) -> Result<()> {
+        require_gte!(
+            ctx.accounts.share_amount(amount)?,
+            min_shares,
+            FlashLoanError::SlippageExceeded
+        );
+        deposit(ctx, amount)
+    }
+
+    /// Mint exactly `share_amount` pool shares,
+    /// failing if more than `max_tokens` tokens would be deposited
+    pub fn mint_shares(ctx: Context<Deposit>, share_amount: u64, max_tokens: u64) -> Result<()> {
+        let amount = ctx.accounts.token_amount_for(share_amount)?;
+        require_gte!(max_tokens, amount, FlashLoanError::SlippageExceeded);
+        ctx.accounts
+            .issue(amount, share_amount, ctx.remaining_accounts)
+    }
+
+    /// Withdraw funds from a lending pool
+    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+        let token_amount = ctx.accounts.token_amount(amount)?;
+        ctx.accounts
+            .redeem(amount, token_amount, ctx.remaining_accounts)
+    }
+
+    /// Withdraw funds from a lending pool, failing if fewer than `min_tokens` tokens are received
+    pub fn withdraw_with_min_tokens(
+        ctx: Context<Withdraw>,
+        amount: u64,
+        min_tokens: u64,
+    ) -> Result<()> {
+        require_gte!(
+            ctx.accounts.token_amount(amount)?,
+            min_tokens,
+            FlashLoanError::SlippageExceeded
+        );
+        withdraw(ctx, amount)
+    }
+
+    /// Withdraw exactly `token_amount` tokens from a lending pool,
+    /// failing if more than `max_shares` pool shares would be burned
+    pub fn withdraw_exact_tokens(
+        ctx: Context<Withdraw>,
+        token_amount: u64,
+        max_shares: u64,
+    ) -> Result<()> {
+        let share_amount = ctx.accounts.share_amount_for(token_amount)?;
+        require_gte!(max_shares, share_amount, FlashLoanError::SlippageExceeded);
+        ctx.accounts
+            .redeem(share_amount, token_amount, ctx.remaining_accounts)
+    }
+
+    /// Borrow funds from a lending pool
+    pub fn borrow(ctx: Context<Borrow>, amount: u64) -> Result<()> {
+        let instructions_sysvar = ctx.accounts.instructions_sysvar.to_account_info();
+
+        // make sure this isn't a cpi call, unless the caller is allowlisted
+        let current_idx = introspection::check_caller(
+            &instructions_sysvar,
+            &ctx.accounts.pool_authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        // make sure the loan is repaid
+        let pool_config = ctx.accounts.pool_config.load()?;
+        let fee = math::loan_fee(
             amount,
+            pool_config.loan_fee_millionths,
+            pool_config.referral_fee_millionths,
         )?;
+        introspection::check_repayment(
+            &instructions_sysvar,
+            current_idx,
+            &ctx.accounts.token_from.key(),
+            &ctx.accounts.pool_authority.key(),
+            amount,
+            pool_config.loan_fee_millionths,
+            pool_config.referral_fee_millionths,
+        )?;
+
+        // the repayment checks only see instructions at the top of the transaction, so a second
+        // loan borrowed through CPI before the repay would be covered by the repay of this one
+        {
+            let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+            if get_stack_height() > TRANSACTION_LEVEL_STACK_HEIGHT {
+                require!(
+                    pool_authority.loan_outstanding == 0,
+                    FlashLoanError::CannotBorrowBeforeRepay
+                );
+            }
+            pool_authority.loan_outstanding = 1;
+        }
 
         // get signer seeds
-        let mint_bytes = ctx.accounts.token_to.mint.to_bytes();
+        let mint_bytes = ctx.accounts.token_from.mint.to_bytes();
         let pool_authority_seeds = [
             POOL_SEED,
             mint_bytes.as_ref(),
             &[ctx.accounts.pool_authority.load()?.bump],
         ];
 
-        // mint new pool share tokens
-        anchor_spl::token::mint_to(
+        // transfer from pool to borrower
+        anchor_spl::token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::MintTo {
-                    mint: ctx.accounts.pool_share_mint.to_account_info(),
-                    to: ctx.accounts.pool_share_token_to.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: ctx.accounts.token_from.to_account_info(),
+                    to: ctx.accounts.token_to.to_account_info(),
                     authority: ctx.accounts.pool_authority.to_account_info(),
                 },
             )
             .with_signer(&[&pool_authority_seeds[..]]),
-            share_amount,
+            amount,
+        )?;
+
+        // update pool statistics
+        let mut pool_stats = ctx.accounts.pool_stats.load_mut()?;
+        pool_stats.total_loans = pool_stats.total_loans.saturating_add(1);
+        pool_stats.total_borrowed = pool_stats.total_borrowed.saturating_add(u128::from(amount));
+        pool_stats.last_loan_slot = Clock::get()?.slot;
+
+        emit_event(
+            &Borrowed {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                borrower: ctx.accounts.borrower.key(),
+                token_to: ctx.accounts.token_to.key(),
+                amount,
+                fee,
+            },
+            ctx.remaining_accounts,
         )?;
 
         Ok(())
     }
 
-    /// Withdraw funds from a lending pool
-    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
-        // calculate token amount
-        // shares * total pool amount / total shares
-        let token_amount = u64::try_from(
-            u128::from(amount) * u128::from(ctx.accounts.token_from.amount)
-                / u128::from(ctx.accounts.pool_share_mint.supply),
-        )
-        .unwrap();
+    /// Borrow another tranche of funds from a lending pool
+    ///
+    /// Unlike `borrow`, this can come between a borrow from the same pool and its repay, in which
+    /// case that single repay must cover every tranche and its fees.
+    pub fn borrow_tranche(ctx: Context<Borrow>, amount: u64) -> Result<()> {
+        borrow(ctx, amount)
+    }
 
-        // burn pool share tokens
-        anchor_spl::token::burn(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                anchor_spl::token::Burn {
-                    mint: ctx.accounts.pool_share_mint.to_account_info(),
-                    from: ctx.accounts.pool_share_token_from.to_account_info(),
-                    authority: ctx.accounts.withdrawer.to_account_info(),
-                },
-            ),
+    /// Borrow funds from a lending pool, recording the vault balance in a loan receipt
+    ///
+    /// Instead of a `repay`, the loan must be followed by a `settle` of the same pool which checks
+    /// that the vault balance grew by the fee, so the funds can be returned by any route.
+    ///
+    /// The receipt is derived from the pool alone, so a pool has at most one receipt loan
+    /// outstanding: a second `borrow_with_receipt` of the pool fails until the first is settled.
+    /// As the settle counts any fee paid into the vault, no other loan or repay of the pool may
+    /// come between the two, and the pool must have no loan outstanding.
+    pub fn borrow_with_receipt(ctx: Context<BorrowWithReceipt>, amount: u64) -> Result<()> {
+        let instructions_sysvar = ctx.accounts.instructions_sysvar.to_account_info();
+
+        // make sure this isn't a cpi call, unless the caller is allowlisted
+        let current_idx = introspection::check_caller(
+            &instructions_sysvar,
+            &ctx.accounts.pool_authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        // make sure the loan is settled
+        introspection::check_settlement(
+            &instructions_sysvar,
+            current_idx,
+            &ctx.accounts.token_from.key(),
+            &ctx.accounts.pool_authority.key(),
+        )?;
+
+        // the settle counts every fee paid into the vault, so the fees of another loan of the pool
+        // would count towards this one
+        {
+            let mut pool_authority = ctx.accounts.pool_authority.load_mut()?;
+            require!(
+                pool_authority.loan_outstanding == 0,
+                FlashLoanError::CannotBorrowBeforeRepay
+            );
+            pool_authority.loan_outstanding = 1;
+        }
+
+        // there is no referrer when settling, so only the loan fee is due
+        let fee = math::loan_fee(
             amount,
+            ctx.accounts.pool_config.load()?.loan_fee_millionths,
+            0,
         )?;
+        let mut loan_receipt = ctx.accounts.loan_receipt.load_init()?;
+        *loan_receipt = LoanReceipt {
+            pool_authority: ctx.accounts.pool_authority.key(),
+            borrower: ctx.accounts.borrower.key(),
+            amount,
+            pre_balance: ctx.accounts.token_from.amount,
+            fee,
+            pool_share_supply: ctx.accounts.pool_share_mint.supply,
+            bump: *ctx.bumps.get("loan_receipt").unwrap(),
+        };
 
         // get signer seeds
         let mint_bytes = ctx.accounts.token_from.mint.to_bytes();
@@ -172,7 +636,7 @@ pub mod flash_loan_mastery {
             &[ctx.accounts.pool_authority.load()?.bump],
         ];
 
-        // transfer from pool
+        // transfer from pool to borrower
         anchor_spl::token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
@@ -183,71 +647,93 @@ pub mod flash_loan_mastery {
                 },
             )
             .with_signer(&[&pool_authority_seeds[..]]),
-            token_amount,
+            amount,
+        )?;
+
+        // update pool statistics
+        let mut pool_stats = ctx.accounts.pool_stats.load_mut()?;
+        pool_stats.total_loans = pool_stats.total_loans.saturating_add(1);
+        pool_stats.total_borrowed = pool_stats.total_borrowed.saturating_add(u128::from(amount));
+        pool_stats.last_loan_slot = Clock::get()?.slot;
+
+        emit_event(
+            &Borrowed {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                borrower: ctx.accounts.borrower.key(),
+                token_to: ctx.accounts.token_to.key(),
+                amount,
+                fee,
+            },
+            ctx.remaining_accounts,
         )?;
 
         Ok(())
     }
 
-    /// Borrow funds from a lending pool
-    pub fn borrow(ctx: Context<Borrow>, amount: u64) -> Result<()> {
-        let instructions_sysvar = ctx.accounts.instructions_sysvar.to_account_info();
-
-        // make sure this isn't a cpi call
-        let current_idx = load_current_index_checked(&instructions_sysvar)? as usize;
-        let current_ixn = load_instruction_at_checked(current_idx, &instructions_sysvar)?;
-        require_keys_eq!(
-            current_ixn.program_id,
-            crate::ID,
-            FlashLoanError::ProgramMismatch
+    /// Settle a loan taken with `borrow_with_receipt` and close its receipt
+    ///
+    /// The vault balance must be at least the balance before the loan plus the fee, and the pool
+    /// share supply must not have changed, deposits made while the vault is short of the loan
+    /// would otherwise mint shares at a discount.
+    pub fn settle(ctx: Context<Settle>) -> Result<()> {
+        let loan_receipt = ctx.accounts.loan_receipt.load()?;
+        let pool_amount = ctx.accounts.token_to.amount;
+        require_gte!(
+            pool_amount,
+            loan_receipt
+                .pre_balance
+                .checked_add(loan_receipt.fee)
+                .ok_or(FlashLoanError::MathOverflow)?,
+            FlashLoanError::IncorrectRepaymentAmount
+        );
+        require_eq!(
+            ctx.accounts.pool_share_mint.supply,
+            loan_receipt.pool_share_supply,
+            FlashLoanError::PoolSharesChanged
         );
+        ctx.accounts.pool_authority.load_mut()?.loan_outstanding = 0;
+
+        // update pool statistics
+        let pool_fee = pool_amount - loan_receipt.pre_balance;
+        let mut pool_stats = ctx.accounts.pool_stats.load_mut()?;
+        pool_stats.total_fees = pool_stats.total_fees.saturating_add(u128::from(pool_fee));
+
+        emit_event(
+            &Settled {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                borrower: ctx.accounts.borrower.key(),
+                amount: loan_receipt.amount,
+                fee: pool_fee,
+                pool_amount,
+                pool_share_supply: ctx.accounts.pool_share_mint.supply,
+            },
+            ctx.remaining_accounts,
+        )?;
 
-        // get expected repay amount
-        let fee = u64::try_from(
-            u128::from(amount) * (LOAN_FEE + REFERRAL_FEE) / (LOAN_FEE_DENOMINATOR * ONE_HUNDRED),
-        )
-        .unwrap();
-        let expected_repayment = amount.checked_add(fee).unwrap();
-
-        // get the ix identifier
-        let borrow_ix_identifier = get_instruction_discriminator(&[b"global:borrow"]);
-        let repay_ix_identifier = get_instruction_discriminator(&[b"global:repay"]);
-
-        let mut ix_index = current_idx;
-        loop {
-            ix_index += 1;
-            if let Ok(ixn) = load_instruction_at_checked(ix_index, &instructions_sysvar) {
-                if ixn.program_id == crate::ID {
-                    let ixn_identifier = u64::from_be_bytes(ixn.data[..8].try_into().unwrap());
-                    // deal with repay instruction
-                    if ixn_identifier == repay_ix_identifier {
-                        require_keys_eq!(
-                            ixn.accounts[2].pubkey,
-                            ctx.accounts.token_from.key(),
-                            FlashLoanError::AddressMismatch
-                        );
-                        require_keys_eq!(
-                            ixn.accounts[3].pubkey,
-                            ctx.accounts.pool_authority.key(),
-                            FlashLoanError::PoolMismatch
-                        );
-                        let repay_ix_amount =
-                            u64::from_le_bytes(ixn.data[8..16].try_into().unwrap());
-                        require_gte!(
-                            repay_ix_amount,
-                            expected_repayment,
-                            FlashLoanError::IncorrectRepaymentAmount
-                        );
-                        // ALL is good :)
-                        break;
-                    } else if ixn_identifier == borrow_ix_identifier {
-                        return Err(error!(FlashLoanError::CannotBorrowBeforeRepay));
-                    }
-                }
-            } else {
-                return Err(error!(FlashLoanError::NoRepaymentInstructionFound));
-            }
-        }
+        Ok(())
+    }
+
+    /// Lend funds to a receiver program within a single instruction
+    ///
+    /// The funds are transferred to `token_to`, then `on_flash_loan` is called on
+    /// `receiver_program` with a `FlashLoanPayload` and the remaining accounts, signed by the flash
+    /// loan signer PDA so the receiver can check the call comes from here. The receiver must pay
+    /// back the loan and the fee to the pool vault before returning. Unlike `borrow`, this can be
+    /// called by other programs, as long as the receiver is a different program: Solana rejects a
+    /// program called back through another one.
+    pub fn flash_loan<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashLoan<'info>>,
+        amount: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        // there is no referrer, so only the loan fee is due
+        let fee = math::loan_fee(
+            amount,
+            ctx.accounts.pool_config.load()?.loan_fee_millionths,
+            0,
+        )?;
+        let pre_balance = ctx.accounts.token_from.amount;
+        let pool_share_supply = ctx.accounts.pool_share_mint.supply;
 
         // get signer seeds
         let mint_bytes = ctx.accounts.token_from.mint.to_bytes();
@@ -257,7 +743,7 @@ pub mod flash_loan_mastery {
             &[ctx.accounts.pool_authority.load()?.bump],
         ];
 
-        // transfer from pool to borrower
+        // transfer from pool to the receiver
         anchor_spl::token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
@@ -271,6 +757,87 @@ pub mod flash_loan_mastery {
             amount,
         )?;
 
+        // call the receiver
+        let payload = FlashLoanPayload {
+            initiator: ctx.accounts.borrower.key(),
+            mint: ctx.accounts.token_from.mint,
+            amount,
+            fee,
+            data,
+        };
+        let ixn = flash_loan_receiver::on_flash_loan_ix(
+            &ctx.accounts.receiver_program.key(),
+            &ctx.accounts.borrower.key(),
+            &ctx.accounts.token_to.key(),
+            &ctx.accounts.token_from.key(),
+            &payload,
+            ctx.remaining_accounts.iter().map(|account| AccountMeta {
+                pubkey: account.key(),
+                is_signer: account.is_signer,
+                is_writable: account.is_writable,
+            }),
+        )?;
+        let mut account_infos = vec![
+            ctx.accounts.borrower.to_account_info(),
+            ctx.accounts.token_to.to_account_info(),
+            ctx.accounts.token_from.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.flash_loan_signer.to_account_info(),
+        ];
+        account_infos.extend_from_slice(ctx.remaining_accounts);
+        // the receiver must not borrow from or deposit into the pool while it holds the loan
+        ctx.accounts
+            .pool_authority
+            .load_mut()?
+            .flash_loan_in_progress = 1;
+        invoke_signed(
+            &ixn,
+            &account_infos,
+            &[&[FLASH_LOAN_SIGNER_SEED, &[FLASH_LOAN_SIGNER_BUMP]]],
+        )?;
+        ctx.accounts
+            .pool_authority
+            .load_mut()?
+            .flash_loan_in_progress = 0;
+
+        // make sure the loan is repaid, shares minted while the vault was short would be too cheap
+        ctx.accounts.token_from.reload()?;
+        ctx.accounts.pool_share_mint.reload()?;
+        let pool_amount = ctx.accounts.token_from.amount;
+        require_gte!(
+            pool_amount,
+            pre_balance
+                .checked_add(fee)
+                .ok_or(FlashLoanError::MathOverflow)?,
+            FlashLoanError::IncorrectRepaymentAmount
+        );
+        require_eq!(
+            ctx.accounts.pool_share_mint.supply,
+            pool_share_supply,
+            FlashLoanError::PoolSharesChanged
+        );
+
+        // update pool statistics
+        let pool_fee = pool_amount - pre_balance;
+        let mut pool_stats = ctx.accounts.pool_stats.load_mut()?;
+        pool_stats.total_loans = pool_stats.total_loans.saturating_add(1);
+        pool_stats.total_borrowed = pool_stats.total_borrowed.saturating_add(u128::from(amount));
+        pool_stats.total_fees = pool_stats.total_fees.saturating_add(u128::from(pool_fee));
+        pool_stats.last_loan_slot = Clock::get()?.slot;
+
+        emit_event(
+            &FlashLoaned {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                borrower: ctx.accounts.borrower.key(),
+                receiver_program: ctx.accounts.receiver_program.key(),
+                amount,
+                fee: pool_fee,
+                pool_amount,
+                pool_share_supply,
+            },
+            ctx.remaining_accounts,
+        )?;
+
         Ok(())
     }
 
@@ -278,30 +845,32 @@ pub mod flash_loan_mastery {
     pub fn repay<'info>(ctx: Context<'_, '_, '_, 'info, Repay<'info>>, amount: u64) -> Result<()> {
         let instructions_sysvar = ctx.accounts.instructions_sysvar.to_account_info();
 
-        // make sure this isn't a cpi call
-        let current_idx =
-            sysvar::instructions::load_current_index_checked(&instructions_sysvar)? as usize;
-        let current_ixn =
-            sysvar::instructions::load_instruction_at_checked(current_idx, &instructions_sysvar)?;
-        require_keys_eq!(
-            current_ixn.program_id,
-            crate::ID,
-            FlashLoanError::ProgramMismatch
-        );
+        // make sure this isn't a cpi call, unless the caller is allowlisted
+        introspection::check_caller(
+            &instructions_sysvar,
+            &ctx.accounts.pool_authority.key(),
+            ctx.remaining_accounts,
+        )?;
+
+        // the loans of the pool are matched to the next repay at the top of the transaction
+        if get_stack_height() == TRANSACTION_LEVEL_STACK_HEIGHT {
+            ctx.accounts.pool_authority.load_mut()?.loan_outstanding = 0;
+        }
 
         // get referral fee
-        let original_amt = LOAN_FEE_DENOMINATOR * ONE_HUNDRED * u128::from(amount)
-            / ((LOAN_FEE_DENOMINATOR * ONE_HUNDRED) + LOAN_FEE + REFERRAL_FEE);
-        let referral_fee =
-            u64::try_from(original_amt * REFERRAL_FEE / (LOAN_FEE_DENOMINATOR * ONE_HUNDRED))
-                .unwrap();
+        let pool_config = ctx.accounts.pool_config.load()?;
+        let split = math::split_repayment(
+            amount,
+            pool_config.loan_fee_millionths,
+            pool_config.referral_fee_millionths,
+        )?;
 
         // should we pay a referral fee?
-        let mut pay_referral_fee = false;
+        let mut referrer = None;
         if let Some(referral_info) = ctx.remaining_accounts.get(0) {
             let referral_token_info = Account::<TokenAccount>::try_from(referral_info);
             if referral_token_info.is_ok() {
-                pay_referral_fee = true;
+                referrer = Some(referral_info.key());
             }
         }
 
@@ -315,10 +884,10 @@ pub mod flash_loan_mastery {
                     authority: ctx.accounts.repayer.to_account_info(),
                 },
             ),
-            amount.checked_sub(referral_fee).unwrap(),
+            split.pool_repayment,
         )?;
         // transfer referral fee
-        if pay_referral_fee {
+        if referrer.is_some() {
             anchor_spl::token::transfer(
                 CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
@@ -328,10 +897,45 @@ pub mod flash_loan_mastery {
                         authority: ctx.accounts.repayer.to_account_info(),
                     },
                 ),
-                referral_fee,
+                split.referral_fee,
             )?;
         }
 
+        // update pool statistics
+        let pool_fee = split.pool_fee;
+        let paid_referral_fee = if referrer.is_some() {
+            split.referral_fee
+        } else {
+            0
+        };
+        let mut pool_stats = ctx.accounts.pool_stats.load_mut()?;
+        pool_stats.total_fees = pool_stats.total_fees.saturating_add(u128::from(pool_fee));
+        pool_stats.total_referral_fees = pool_stats
+            .total_referral_fees
+            .saturating_add(u128::from(paid_referral_fee));
+
+        emit_event(
+            &Repaid {
+                pool_authority: ctx.accounts.pool_authority.key(),
+                repayer: ctx.accounts.repayer.key(),
+                amount: split
+                    .pool_repayment
+                    .checked_add(paid_referral_fee)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+                fee: pool_fee,
+                referral_fee: paid_referral_fee,
+                referrer,
+                pool_amount: ctx
+                    .accounts
+                    .token_to
+                    .amount
+                    .checked_add(split.pool_repayment)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+                pool_share_supply: ctx.accounts.pool_share_mint.supply,
+            },
+            ctx.remaining_accounts,
+        )?;
+
         Ok(())
     }
 }
@@ -347,10 +951,137 @@ pub struct PoolAuthority {
     pub pool_share_mint: Pubkey,
     /// The PDA bump
     pub bump: u8,
+    /// The entity allowed to administer the pool
+    pub admin: Pubkey,
+    /// The entity proposed as the next admin, if any
+    pub pending_admin: Pubkey,
+    /// The entity allowed to pause the pool in an emergency
+    pub guardian: Pubkey,
+    /// Whether borrowing and depositing are paused, 1 if paused
+    pub paused: u8,
+    /// 1 while a `flash_loan` of the pool is calling its receiver, borrowing and depositing are
+    /// rejected meanwhile
+    pub flash_loan_in_progress: u8,
+    /// 1 from a borrow of the pool until the `repay` at the top of the transaction paying it back,
+    /// or from a `borrow_with_receipt` until its `settle`, borrowing through CPI, flash loans and
+    /// depositing are rejected meanwhile
+    pub loan_outstanding: u8,
+    /// 1 if the pool was created before pools had an admin and migrated by
+    /// `migrate_pool_authority`, only such pools can get their admin from `init_pool_admin`
+    pub migrated: u8,
 }
 
 impl PoolAuthority {
-    const LEN: usize = 8 + 1 + 32 + 32;
+    const LEN: usize = 8 + 1 + 32 + 32 + 32 + 32 + 32 + 1 + 1 + 1 + 1;
+    /// The size of `PoolAuthority` accounts created before pools had an admin
+    const LEGACY_LEN: usize = 8 + 1 + 32 + 32;
+
+    /// Load a pool authority, failing with `PoolNotMigrated` instead of panicking if it still
+    /// has the legacy size
+    pub fn try_load<'a>(
+        loader: &'a AccountLoader<PoolAuthority>,
+    ) -> Result<std::cell::Ref<'a, PoolAuthority>> {
+        require_eq!(
+            loader.as_ref().data_len(),
+            PoolAuthority::LEN,
+            FlashLoanError::PoolNotMigrated
+        );
+        loader.load()
+    }
+}
+
+/// `PoolConfig` account
+#[account(zero_copy)]
+#[repr(packed)]
+#[derive(Debug)]
+pub struct PoolConfig {
+    /// The `pool_authority` this config belongs to
+    pub pool_authority: Pubkey,
+    /// The fee paid to the pool, in millionths of the borrowed amount
+    pub loan_fee_millionths: u64,
+    /// The fee paid to referrers, in millionths of the borrowed amount
+    pub referral_fee_millionths: u64,
+    /// The PDA bump
+    pub bump: u8,
+}
+
+impl PoolConfig {
+    const LEN: usize = 8 + 32 + 8 + 8 + 1;
+}
+
+/// `PoolStats` account
+#[account(zero_copy)]
+#[repr(packed)]
+#[derive(Debug)]
+pub struct PoolStats {
+    /// The `pool_authority` these statistics belong to
+    pub pool_authority: Pubkey,
+    /// The number of flash loans taken from the pool
+    pub total_loans: u64,
+    /// The total amount borrowed from the pool
+    pub total_borrowed: u128,
+    /// The total fees paid to the pool
+    pub total_fees: u128,
+    /// The total fees paid to referrers
+    pub total_referral_fees: u128,
+    /// The slot of the most recent flash loan
+    pub last_loan_slot: u64,
+    /// The PDA bump
+    pub bump: u8,
+}
+
+impl PoolStats {
+    const LEN: usize = 8 + 32 + 8 + 16 + 16 + 16 + 8 + 1;
+}
+
+/// `LoanReceipt` account, it only exists between a `borrow_with_receipt` and its `settle`
+///
+/// There is one receipt address per pool, it is seeded with the `pool_authority` only.
+#[account(zero_copy)]
+#[repr(packed)]
+#[derive(Debug)]
+pub struct LoanReceipt {
+    /// The `pool_authority` of the pool lending the funds
+    pub pool_authority: Pubkey,
+    /// The entity that borrowed the funds, it receives the rent when the receipt is closed
+    pub borrower: Pubkey,
+    /// The amount borrowed
+    pub amount: u64,
+    /// The pool token balance before the loan
+    pub pre_balance: u64,
+    /// The fee due to the pool
+    pub fee: u64,
+    /// The pool share supply when the funds were borrowed
+    pub pool_share_supply: u64,
+    /// The PDA bump
+    pub bump: u8,
+}
+
+impl LoanReceipt {
+    const LEN: usize = 8 + 32 + 32 + 8 + 8 + 8 + 8 + 1;
+}
+
+/// `CpiAllowlist` account, the programs allowed to call `borrow` and `repay` through CPI
+#[account(zero_copy)]
+#[repr(packed)]
+#[derive(Debug)]
+pub struct CpiAllowlist {
+    /// The `pool_authority` this allowlist belongs to
+    pub pool_authority: Pubkey,
+    /// The allowed programs, unused entries are the default `Pubkey`
+    pub programs: [Pubkey; MAX_CPI_PROGRAMS],
+    /// The PDA bump
+    pub bump: u8,
+}
+
+impl CpiAllowlist {
+    const LEN: usize = 8 + 32 + 32 * MAX_CPI_PROGRAMS + 1;
+
+    /// Whether `program` is allowed to call this program through CPI
+    #[must_use]
+    pub fn contains(&self, program: &Pubkey) -> bool {
+        *program != Pubkey::default() && self.programs.contains(program)
+    }
 }
 
 /// Accounts for `InitPool`
@@ -396,24 +1127,282 @@ pub struct InitPool<'info> {
     pub system_program: Program<'info, System>,
 }
 
-/// Accounts for `Deposit`
-// `Mint` and `Token` don't implement `Debug`...
+/// Accounts for `InitPoolConfig`
 #[allow(missing_debug_implementations)]
 #[derive(Accounts)]
-pub struct Deposit<'info> {
-    /// The entity depositing funds into the pool
-    pub depositor: Signer<'info>,
-
-    /// The token to deposit into the pool
-    /// CHECK: checked in token program
+pub struct InitPoolConfig<'info> {
+    /// The funder for the `pool_config` account
     #[account(mut)]
-    pub token_from: UncheckedAccount<'info>,
+    pub funder: Signer<'info>,
 
-    /// The token to receive tokens deposited into the pool
+    /// The pool authority
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The pool config
     #[account(
-        mut,
-        constraint = token_to.owner == pool_authority.key() @FlashLoanError::OwnerMismatch,
-        address = get_associated_token_address(pool_authority.as_ref().key, &pool_authority.load()?.mint) @FlashLoanError::AddressMismatch,
+        init,
+        payer = funder,
+        space = PoolConfig::LEN,
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+
+    /// The Solana System program
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts for `InitPoolStats`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct InitPoolStats<'info> {
+    /// The funder for the `pool_stats` account
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    /// The pool authority
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The pool statistics
+    #[account(
+        init,
+        payer = funder,
+        space = PoolStats::LEN,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
+
+    /// The Solana System program
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts for `SetPoolConfig`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct SetPoolConfig<'info> {
+    /// The pool admin
+    pub admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(constraint = PoolAuthority::try_load(&pool_authority)?.admin == admin.key() @FlashLoanError::AdminOnly)]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The pool config
+    #[account(
+        mut,
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_config.load()?.bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+}
+
+/// Accounts for `InitCpiAllowlist`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct InitCpiAllowlist<'info> {
+    /// The funder for the `cpi_allowlist` account
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    /// The pool authority
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The CPI allowlist
+    #[account(
+        init,
+        payer = funder,
+        space = CpiAllowlist::LEN,
+        seeds = [
+            CPI_ALLOWLIST_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub cpi_allowlist: AccountLoader<'info, CpiAllowlist>,
+
+    /// The Solana System program
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts for `AddCpiProgram` and `RemoveCpiProgram`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct UpdateCpiAllowlist<'info> {
+    /// The pool admin
+    pub admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(constraint = PoolAuthority::try_load(&pool_authority)?.admin == admin.key() @FlashLoanError::AdminOnly)]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The CPI allowlist
+    #[account(
+        mut,
+        seeds = [
+            CPI_ALLOWLIST_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = cpi_allowlist.load()?.bump,
+    )]
+    pub cpi_allowlist: AccountLoader<'info, CpiAllowlist>,
+}
+
+/// Accounts for `MigratePoolAuthority`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct MigratePoolAuthority<'info> {
+    /// The funder for the additional space of the `pool_authority` account
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    /// The mint representing the token that is borrowed via flash loans
+    pub mint: Account<'info, Mint>,
+
+    /// The pool authority
+    /// CHECK: checked with seeds, owner & in the instruction
+    #[account(
+        mut,
+        owner = crate::ID,
+        seeds = [
+            POOL_SEED,
+            mint.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    /// The Solana System program
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts for `InitPoolAdmin`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct InitPoolAdmin<'info> {
+    /// The upgrade authority of this program
+    pub upgrade_authority: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.migrated == 1 @FlashLoanError::NotLegacyPool,
+        constraint = PoolAuthority::try_load(&pool_authority)?.admin == Pubkey::default() @FlashLoanError::AlreadyMigrated,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// This program
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @FlashLoanError::AddressMismatch)]
+    pub program: Program<'info, crate::program::FlashLoanMastery>,
+
+    /// The program data account of this program
+    #[account(constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key()) @FlashLoanError::AdminOnly)]
+    pub program_data: Account<'info, ProgramData>,
+}
+
+/// Accounts for `ProposeAdmin`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct ProposeAdmin<'info> {
+    /// The pool admin
+    pub admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.admin == admin.key() @FlashLoanError::AdminOnly,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+}
+
+/// Accounts for `AcceptAdmin`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    /// The proposed pool admin
+    pub pending_admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.pending_admin == pending_admin.key() @FlashLoanError::AdminOnly,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+}
+
+/// Accounts for `SetGuardian`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct SetGuardian<'info> {
+    /// The pool admin
+    pub admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.admin == admin.key() @FlashLoanError::AdminOnly,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+}
+
+/// Accounts for `Pause`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct Pause<'info> {
+    /// The pool guardian or admin
+    pub guardian: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.guardian == guardian.key()
+            || PoolAuthority::try_load(&pool_authority)?.admin == guardian.key() @FlashLoanError::GuardianOnly,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+}
+
+/// Accounts for `Unpause`
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct Unpause<'info> {
+    /// The pool admin
+    pub admin: Signer<'info>,
+
+    /// The pool authority
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.admin == admin.key() @FlashLoanError::AdminOnly,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+}
+
+/// Accounts for `Deposit`
+// `Mint` and `Token` don't implement `Debug`...
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    /// The entity depositing funds into the pool
+    pub depositor: Signer<'info>,
+
+    /// The token to deposit into the pool
+    /// CHECK: checked in token program
+    #[account(mut)]
+    pub token_from: UncheckedAccount<'info>,
+
+    /// The token to receive tokens deposited into the pool
+    #[account(
+        mut,
+        constraint = token_to.owner == pool_authority.key() @FlashLoanError::OwnerMismatch,
+        address = get_associated_token_address(pool_authority.as_ref().key, &PoolAuthority::try_load(&pool_authority)?.mint) @FlashLoanError::AddressMismatch,
     )]
     pub token_to: Account<'info, TokenAccount>,
 
@@ -423,18 +1412,21 @@ pub struct Deposit<'info> {
     pub pool_share_token_to: UncheckedAccount<'info>,
 
     /// The mint of the token representing shares in the pool
-    #[account(mut, address = pool_authority.load()?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    #[account(mut, address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
     pub pool_share_mint: Account<'info, Mint>,
 
     /// The pool authority
     /// CHECK: checked with seeds & constraints
     #[account(
         address = pool_share_mint.mint_authority.unwrap() @FlashLoanError::AddressMismatch,
+        constraint = PoolAuthority::try_load(&pool_authority)?.paused == 0 @FlashLoanError::PoolPaused,
+        constraint = PoolAuthority::try_load(&pool_authority)?.flash_loan_in_progress == 0 @FlashLoanError::FlashLoanInProgress,
+        constraint = PoolAuthority::try_load(&pool_authority)?.loan_outstanding == 0 @FlashLoanError::LoanOutstanding,
         seeds = [
             POOL_SEED,
             token_to.mint.key().as_ref(),
         ],
-        bump = pool_authority.load()?.bump,
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
     )]
     pub pool_authority: AccountLoader<'info, PoolAuthority>,
 
@@ -442,6 +1434,87 @@ pub struct Deposit<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+impl Deposit<'_> {
+    /// Get the amount of pool shares minted for a deposit of `amount`
+    pub fn share_amount(&self, amount: u64) -> Result<u64> {
+        math::deposit_shares(amount, self.token_to.amount, self.pool_share_mint.supply)
+    }
+
+    /// Get the amount of tokens to deposit to mint exactly `share_amount` pool shares
+    pub fn token_amount_for(&self, share_amount: u64) -> Result<u64> {
+        math::mint_shares_cost(
+            share_amount,
+            self.token_to.amount,
+            self.pool_share_mint.supply,
+        )
+    }
+
+    /// Transfer `amount` tokens into the pool and mint `share_amount` pool shares
+    fn issue(
+        &self,
+        amount: u64,
+        share_amount: u64,
+        remaining_accounts: &[AccountInfo],
+    ) -> Result<()> {
+        // transfer to pool
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: self.token_from.to_account_info(),
+                    to: self.token_to.to_account_info(),
+                    authority: self.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        // get signer seeds
+        let mint_bytes = self.token_to.mint.to_bytes();
+        let pool_authority_seeds = [
+            POOL_SEED,
+            mint_bytes.as_ref(),
+            &[self.pool_authority.load()?.bump],
+        ];
+
+        // mint new pool share tokens
+        anchor_spl::token::mint_to(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                anchor_spl::token::MintTo {
+                    mint: self.pool_share_mint.to_account_info(),
+                    to: self.pool_share_token_to.to_account_info(),
+                    authority: self.pool_authority.to_account_info(),
+                },
+            )
+            .with_signer(&[&pool_authority_seeds[..]]),
+            share_amount,
+        )?;
+
+        emit_event(
+            &Deposited {
+                pool_authority: self.pool_authority.key(),
+                depositor: self.depositor.key(),
+                amount,
+                share_amount,
+                pool_amount: self
+                    .token_to
+                    .amount
+                    .checked_add(amount)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+                pool_share_supply: self
+                    .pool_share_mint
+                    .supply
+                    .checked_add(share_amount)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+            },
+            remaining_accounts,
+        )?;
+
+        Ok(())
+    }
+}
+
 /// Accounts for `Withdraw`
 // `Mint` and `Token` don't implement `Debug`...
 #[allow(missing_debug_implementations)]
@@ -465,7 +1538,7 @@ pub struct Withdraw<'info> {
     pub pool_share_token_from: UncheckedAccount<'info>,
 
     /// The mint of the token representing shares in the pool
-    #[account(mut, address = pool_authority.load()?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    #[account(mut, address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
     pub pool_share_mint: Account<'info, Mint>,
 
     /// The pool authority
@@ -476,7 +1549,7 @@ pub struct Withdraw<'info> {
             POOL_SEED,
             token_from.mint.key().as_ref(),
         ],
-        bump = pool_authority.load()?.bump,
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
     )]
     pub pool_authority: AccountLoader<'info, PoolAuthority>,
 
@@ -484,6 +1557,87 @@ pub struct Withdraw<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+impl Withdraw<'_> {
+    /// Get the amount of tokens received for redeeming `amount` pool shares
+    pub fn token_amount(&self, amount: u64) -> Result<u64> {
+        math::redeem_tokens(amount, self.token_from.amount, self.pool_share_mint.supply)
+    }
+
+    /// Get the amount of pool shares to burn to receive exactly `token_amount` tokens
+    pub fn share_amount_for(&self, token_amount: u64) -> Result<u64> {
+        math::withdraw_shares(
+            token_amount,
+            self.token_from.amount,
+            self.pool_share_mint.supply,
+        )
+    }
+
+    /// Burn `share_amount` pool shares and transfer `token_amount` tokens out of the pool
+    fn redeem(
+        &self,
+        share_amount: u64,
+        token_amount: u64,
+        remaining_accounts: &[AccountInfo],
+    ) -> Result<()> {
+        // burn pool share tokens
+        anchor_spl::token::burn(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                anchor_spl::token::Burn {
+                    mint: self.pool_share_mint.to_account_info(),
+                    from: self.pool_share_token_from.to_account_info(),
+                    authority: self.withdrawer.to_account_info(),
+                },
+            ),
+            share_amount,
+        )?;
+
+        // get signer seeds
+        let mint_bytes = self.token_from.mint.to_bytes();
+        let pool_authority_seeds = [
+            POOL_SEED,
+            mint_bytes.as_ref(),
+            &[self.pool_authority.load()?.bump],
+        ];
+
+        // transfer from pool
+        anchor_spl::token::transfer(
+            CpiContext::new(
+                self.token_program.to_account_info(),
+                anchor_spl::token::Transfer {
+                    from: self.token_from.to_account_info(),
+                    to: self.token_to.to_account_info(),
+                    authority: self.pool_authority.to_account_info(),
+                },
+            )
+            .with_signer(&[&pool_authority_seeds[..]]),
+            token_amount,
+        )?;
+
+        emit_event(
+            &Withdrawn {
+                pool_authority: self.pool_authority.key(),
+                withdrawer: self.withdrawer.key(),
+                amount: token_amount,
+                share_amount,
+                pool_amount: self
+                    .token_from
+                    .amount
+                    .checked_sub(token_amount)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+                pool_share_supply: self
+                    .pool_share_mint
+                    .supply
+                    .checked_sub(share_amount)
+                    .ok_or(FlashLoanError::MathOverflow)?,
+            },
+            remaining_accounts,
+        )?;
+
+        Ok(())
+    }
+}
+
 /// Accounts for `Borrow`
 // `Mint` and `Token` don't implement `Debug`...
 #[allow(missing_debug_implementations)]
@@ -504,11 +1658,14 @@ pub struct Borrow<'info> {
     /// The pool authority
     /// CHECK: checked with seeds & in token program
     #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.paused == 0 @FlashLoanError::PoolPaused,
+        constraint = PoolAuthority::try_load(&pool_authority)?.flash_loan_in_progress == 0 @FlashLoanError::FlashLoanInProgress,
         seeds = [
             POOL_SEED,
             token_from.mint.key().as_ref(),
         ],
-        bump = pool_authority.load()?.bump,
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
     )]
     pub pool_authority: AccountLoader<'info, PoolAuthority>,
 
@@ -519,6 +1676,249 @@ pub struct Borrow<'info> {
 
     /// The [Token] program
     pub token_program: Program<'info, Token>,
+
+    /// The pool config
+    #[account(
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_config.load()?.bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+
+    /// The pool statistics
+    #[account(
+        mut,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_stats.load()?.bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
+}
+
+/// Accounts for `BorrowWithReceipt`
+// `Mint` and `Token` don't implement `Debug`...
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct BorrowWithReceipt<'info> {
+    /// The entity borrowing funds from the pool, it pays for the loan receipt
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+
+    /// The token to borrow from the pool
+    #[account(
+        mut,
+        address = get_associated_token_address(pool_authority.as_ref().key, &PoolAuthority::try_load(&pool_authority)?.mint) @FlashLoanError::AddressMismatch,
+    )]
+    pub token_from: Account<'info, TokenAccount>,
+
+    /// The token to receive tokens borrowed from the pool
+    /// CHECK: checked in token program
+    #[account(mut)]
+    pub token_to: UncheckedAccount<'info>,
+
+    /// The pool authority
+    /// CHECK: checked with seeds & in token program
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.paused == 0 @FlashLoanError::PoolPaused,
+        constraint = PoolAuthority::try_load(&pool_authority)?.flash_loan_in_progress == 0 @FlashLoanError::FlashLoanInProgress,
+        seeds = [
+            POOL_SEED,
+            token_from.mint.key().as_ref(),
+        ],
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// Solana Instructions Sysvar
+    /// CHECK: Checked using address
+    #[account(address = sysvar::instructions::ID @FlashLoanError::AddressMismatch)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+
+    /// The [Token] program
+    pub token_program: Program<'info, Token>,
+
+    /// The pool config
+    #[account(
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_config.load()?.bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+
+    /// The pool statistics
+    #[account(
+        mut,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_stats.load()?.bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
+
+    /// The mint of the token representing shares in the pool
+    #[account(address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    pub pool_share_mint: Account<'info, Mint>,
+
+    /// The loan receipt
+    #[account(
+        init,
+        payer = borrower,
+        space = LoanReceipt::LEN,
+        seeds = [
+            LOAN_RECEIPT_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub loan_receipt: AccountLoader<'info, LoanReceipt>,
+
+    /// The Solana System program
+    pub system_program: Program<'info, System>,
+}
+
+/// Accounts for `Settle`
+// `Mint` and `Token` don't implement `Debug`...
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct Settle<'info> {
+    /// The entity that borrowed funds from the pool
+    /// CHECK: checked against the loan receipt
+    #[account(mut)]
+    pub borrower: UncheckedAccount<'info>,
+
+    /// The loan receipt
+    #[account(
+        mut,
+        close = borrower,
+        constraint = loan_receipt.load()?.borrower == borrower.key() @FlashLoanError::AddressMismatch,
+        seeds = [
+            LOAN_RECEIPT_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = loan_receipt.load()?.bump,
+    )]
+    pub loan_receipt: AccountLoader<'info, LoanReceipt>,
+
+    /// The token account holding the pool funds
+    #[account(
+        address = get_associated_token_address(pool_authority.as_ref().key, &PoolAuthority::try_load(&pool_authority)?.mint) @FlashLoanError::AddressMismatch,
+    )]
+    pub token_to: Account<'info, TokenAccount>,
+
+    /// The pool authority
+    /// CHECK: checked with seeds
+    #[account(
+        mut,
+        seeds = [
+            POOL_SEED,
+            token_to.mint.key().as_ref(),
+        ],
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The mint of the token representing shares in the pool
+    #[account(address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    pub pool_share_mint: Account<'info, Mint>,
+
+    /// The pool statistics
+    #[account(
+        mut,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_stats.load()?.bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
+}
+
+/// Accounts for `FlashLoan`
+///
+/// The receiver program is called with the accounts of the `flash_loan_receiver` interface,
+/// `borrower` is the initiator and `token_to` the token account holding the borrowed funds.
+// `Mint` and `Token` don't implement `Debug`...
+#[allow(missing_debug_implementations)]
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    /// The entity borrowing funds from the pool
+    pub borrower: Signer<'info>,
+
+    /// The token to borrow from the pool
+    #[account(
+        mut,
+        address = get_associated_token_address(pool_authority.as_ref().key, &PoolAuthority::try_load(&pool_authority)?.mint) @FlashLoanError::AddressMismatch,
+    )]
+    pub token_from: Account<'info, TokenAccount>,
+
+    /// The token to receive tokens borrowed from the pool
+    /// CHECK: checked in token program
+    #[account(mut)]
+    pub token_to: UncheckedAccount<'info>,
+
+    /// The pool authority
+    /// CHECK: checked with seeds & in token program
+    #[account(
+        mut,
+        constraint = PoolAuthority::try_load(&pool_authority)?.paused == 0 @FlashLoanError::PoolPaused,
+        constraint = PoolAuthority::try_load(&pool_authority)?.flash_loan_in_progress == 0 @FlashLoanError::FlashLoanInProgress,
+        constraint = PoolAuthority::try_load(&pool_authority)?.loan_outstanding == 0 @FlashLoanError::LoanOutstanding,
+        seeds = [
+            POOL_SEED,
+            token_from.mint.key().as_ref(),
+        ],
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
+    )]
+    pub pool_authority: AccountLoader<'info, PoolAuthority>,
+
+    /// The program called to use and repay the loan
+    /// CHECK: any program may receive a loan, the repayment is checked after calling it
+    #[account(executable)]
+    pub receiver_program: UncheckedAccount<'info>,
+
+    /// The PDA signing the call to the receiver, so it can check its caller
+    /// CHECK: checked with seeds
+    #[account(
+        seeds = [FLASH_LOAN_SIGNER_SEED],
+        bump = FLASH_LOAN_SIGNER_BUMP,
+    )]
+    pub flash_loan_signer: UncheckedAccount<'info>,
+
+    /// The [Token] program
+    pub token_program: Program<'info, Token>,
+
+    /// The pool config
+    #[account(
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_config.load()?.bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+
+    /// The mint of the token representing shares in the pool
+    #[account(address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    pub pool_share_mint: Account<'info, Mint>,
+
+    /// The pool statistics
+    #[account(
+        mut,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_stats.load()?.bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
 }
 
 /// Accounts for `Repay`
@@ -544,11 +1944,12 @@ pub struct Repay<'info> {
     /// The pool authority
     /// CHECK: checked with seeds & in token program
     #[account(
+        mut,
         seeds = [
             POOL_SEED,
             token_to.mint.key().as_ref(),
         ],
-        bump = pool_authority.load()?.bump,
+        bump = PoolAuthority::try_load(&pool_authority)?.bump,
     )]
     pub pool_authority: AccountLoader<'info, PoolAuthority>,
 
@@ -559,6 +1960,167 @@ pub struct Repay<'info> {
 
     /// The [Token] program
     pub token_program: Program<'info, Token>,
+
+    /// The pool config
+    #[account(
+        seeds = [
+            POOL_CONFIG_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_config.load()?.bump,
+    )]
+    pub pool_config: AccountLoader<'info, PoolConfig>,
+
+    /// The mint of the token representing shares in the pool
+    #[account(address = PoolAuthority::try_load(&pool_authority)?.pool_share_mint @FlashLoanError::AddressMismatch)]
+    pub pool_share_mint: Account<'info, Mint>,
+
+    /// The pool statistics
+    #[account(
+        mut,
+        seeds = [
+            POOL_STATS_SEED,
+            pool_authority.key().as_ref(),
+        ],
+        bump = pool_stats.load()?.bump,
+    )]
+    pub pool_stats: AccountLoader<'info, PoolStats>,
+}
+
+/// Emitted when a lending pool is initialized
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct PoolInitialized {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The token mint
+    pub mint: Pubkey,
+    /// The `pool_share_mint`
+    pub pool_share_mint: Pubkey,
+    /// The pool admin
+    pub admin: Pubkey,
+}
+
+/// Emitted when funds are deposited into a lending pool
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Deposited {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that deposited funds
+    pub depositor: Pubkey,
+    /// The amount of tokens deposited
+    pub amount: u64,
+    /// The amount of pool shares minted
+    pub share_amount: u64,
+    /// The pool token balance after the deposit
+    pub pool_amount: u64,
+    /// The pool share supply after the deposit
+    pub pool_share_supply: u64,
+}
+
+/// Emitted when funds are withdrawn from a lending pool
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Withdrawn {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that withdrew funds
+    pub withdrawer: Pubkey,
+    /// The amount of tokens withdrawn
+    pub amount: u64,
+    /// The amount of pool shares burned
+    pub share_amount: u64,
+    /// The pool token balance after the withdrawal
+    pub pool_amount: u64,
+    /// The pool share supply after the withdrawal
+    pub pool_share_supply: u64,
+}
+
+/// Emitted when funds are borrowed from a lending pool
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Borrowed {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that borrowed funds
+    pub borrower: Pubkey,
+    /// The token account that received the borrowed funds
+    pub token_to: Pubkey,
+    /// The amount of tokens borrowed
+    pub amount: u64,
+    /// The maximum fee due on repayment, including the referral fee
+    pub fee: u64,
+}
+
+/// Emitted when funds are repaid to a lending pool
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Repaid {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that repaid funds
+    pub repayer: Pubkey,
+    /// The amount transferred by the repayer, including fees, the referral fee is only
+    /// transferred when there is a referrer
+    pub amount: u64,
+    /// The fee paid to the pool
+    pub fee: u64,
+    /// The fee paid to the referrer
+    pub referral_fee: u64,
+    /// The token account that received the referral fee, if any
+    pub referrer: Option<Pubkey>,
+    /// The pool token balance after the repayment
+    pub pool_amount: u64,
+    /// The pool share supply
+    ///
+    /// Clients compute the share price from the raw balances, including the virtual offsets, as
+    /// `(pool_amount + VIRTUAL_ASSETS) / (pool_share_supply + VIRTUAL_SHARES)`.
+    pub pool_share_supply: u64,
+}
+
+/// Emitted when a loan taken with `borrow_with_receipt` is settled
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settled {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that borrowed funds
+    pub borrower: Pubkey,
+    /// The amount of tokens borrowed
+    pub amount: u64,
+    /// The growth of the pool token balance over the loan
+    pub fee: u64,
+    /// The pool token balance after the loan
+    pub pool_amount: u64,
+    /// The pool share supply
+    ///
+    /// Clients compute the share price from the raw balances, including the virtual offsets, as
+    /// `(pool_amount + VIRTUAL_ASSETS) / (pool_share_supply + VIRTUAL_SHARES)`.
+    pub pool_share_supply: u64,
+}
+
+/// Emitted when a loan taken with `flash_loan` is repaid
+#[event]
+#[derive(Debug, PartialEq, Eq)]
+pub struct FlashLoaned {
+    /// The pool authority
+    pub pool_authority: Pubkey,
+    /// The entity that borrowed funds
+    pub borrower: Pubkey,
+    /// The program that received the loan
+    pub receiver_program: Pubkey,
+    /// The amount of tokens borrowed
+    pub amount: u64,
+    /// The growth of the pool token balance over the loan
+    pub fee: u64,
+    /// The pool token balance after the loan
+    pub pool_amount: u64,
+    /// The pool share supply
+    ///
+    /// Clients compute the share price from the raw balances, including the virtual offsets, as
+    /// `(pool_amount + VIRTUAL_ASSETS) / (pool_share_supply + VIRTUAL_SHARES)`.
+    pub pool_share_supply: u64,
 }
 
 /// Errors for this program
@@ -582,4 +2144,82 @@ pub enum FlashLoanError {
     NoRepaymentInstructionFound,
     #[msg("The repayment amount is incorrect")]
     IncorrectRepaymentAmount,
+    #[msg("Fee is outside the allowed bounds")]
+    InvalidFee,
+    #[msg("Only the admin can perform this action")]
+    AdminOnly,
+    #[msg("The pool authority has already been migrated")]
+    AlreadyMigrated,
+    #[msg("Only the guardian can perform this action")]
+    GuardianOnly,
+    #[msg("The pool is paused")]
+    PoolPaused,
+    #[msg("The event authority or program account is missing")]
+    MissingEventAccounts,
+    #[msg("The deposit is too small to mint any pool shares")]
+    ZeroSharesMinted,
+    #[msg("The amount received is below the minimum")]
+    SlippageExceeded,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("The pool has no shares")]
+    EmptyPool,
+    #[msg("The amount must be greater than zero")]
+    ZeroAmount,
+    #[msg("The repay instruction could not be decoded")]
+    MalformedRepayInstruction,
+    #[msg("The borrow instruction could not be decoded")]
+    MalformedBorrowInstruction,
+    #[msg("The settle instruction could not be decoded")]
+    MalformedSettleInstruction,
+    #[msg("The pool share supply changed during the loan")]
+    PoolSharesChanged,
+    #[msg("The CPI allowlist is full")]
+    CpiAllowlistFull,
+    #[msg("The pool authority must be migrated first")]
+    PoolNotMigrated,
+    #[msg("A flash loan of the pool is in progress")]
+    FlashLoanInProgress,
+    #[msg("The pool was not created before pools had an admin")]
+    NotLegacyPool,
+    #[msg("A loan of the pool is outstanding")]
+    LoanOutstanding,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_authority_bump() {
+        let (event_authority, bump) =
+            Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &crate::ID);
+        assert_eq!(bump, EVENT_AUTHORITY_BUMP);
+        assert_eq!(super::event_authority().unwrap(), event_authority);
+    }
+
+    #[test]
+    fn test_receiver_addresses() {
+        assert_eq!(flash_loan_receiver::flash_loan_mastery::ID, crate::ID);
+        assert_eq!(
+            flash_loan_receiver::flash_loan_mastery::POOL_SEED,
+            POOL_SEED
+        );
+        let mint = Pubkey::new_unique();
+        let (pool_authority, _) =
+            Pubkey::find_program_address(&[POOL_SEED, mint.as_ref()], &crate::ID);
+        assert_eq!(
+            flash_loan_receiver::pool_vault_address(&mint),
+            get_associated_token_address(&pool_authority, &mint)
+        );
+
+        // the bump is the one `find_program_address` gives, which anchor's `seeds` checks
+        assert_eq!(
+            Pubkey::find_program_address(&[FLASH_LOAN_SIGNER_SEED], &crate::ID),
+            (
+                flash_loan_receiver::flash_loan_signer_address().unwrap(),
+                FLASH_LOAN_SIGNER_BUMP
+            )
+        );
+    }
 }
@@ -0,0 +1,252 @@
+//! Share and fee math
+//!
+//! These functions are pure and return a `FlashLoanError` instead of panicking, so a bad
+//! input fails with an error that can be told apart from other program failures.
+
+use crate::{FlashLoanError, FEE_DENOMINATOR, VIRTUAL_ASSETS, VIRTUAL_SHARES};
+use anchor_lang::prelude::*;
+
+/// How a repayment is split between the pool and a referrer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepaymentSplit {
+    /// The amount that was borrowed
+    pub principal: u64,
+    /// The amount transferred into the pool
+    pub pool_repayment: u64,
+    /// The fee paid to the pool
+    pub pool_fee: u64,
+    /// The fee paid to the referrer, if there is one
+    pub referral_fee: u64,
+}
+
+/// Get the amount of pool shares minted for a deposit of `amount`, rounded down
+///
+/// This is `amount * (total shares + virtual shares) / (total pool amount + virtual assets)`.
+pub fn deposit_shares(amount: u64, pool_amount: u64, share_supply: u64) -> Result<u64> {
+    require_gt!(amount, 0, FlashLoanError::ZeroAmount);
+    mul_div(
+        amount,
+        u128::from(share_supply) + VIRTUAL_SHARES,
+        u128::from(pool_amount) + VIRTUAL_ASSETS,
+        false,
+    )
+}
+
+/// Get the amount of tokens to deposit to mint exactly `share_amount` pool shares, rounded up
+pub fn mint_shares_cost(share_amount: u64, pool_amount: u64, share_supply: u64) -> Result<u64> {
+    require_gt!(share_amount, 0, FlashLoanError::ZeroAmount);
+    mul_div(
+        share_amount,
+        u128::from(pool_amount) + VIRTUAL_ASSETS,
+        u128::from(share_supply) + VIRTUAL_SHARES,
+        true,
+    )
+}
+
+/// Get the amount of tokens received for redeeming `share_amount` pool shares, rounded down
+///
+/// This is `shares * (total pool amount + virtual assets) / (total shares + virtual shares)`.
+pub fn redeem_tokens(share_amount: u64, pool_amount: u64, share_supply: u64) -> Result<u64> {
+    require_gt!(share_amount, 0, FlashLoanError::ZeroAmount);
+    require_gt!(share_supply, 0, FlashLoanError::EmptyPool);
+    mul_div(
+        share_amount,
+        u128::from(pool_amount) + VIRTUAL_ASSETS,
+        u128::from(share_supply) + VIRTUAL_SHARES,
+        false,
+    )
+}
+
+/// Get the amount of pool shares to burn to withdraw exactly `amount`, rounded up
+pub fn withdraw_shares(amount: u64, pool_amount: u64, share_supply: u64) -> Result<u64> {
+    require_gt!(amount, 0, FlashLoanError::ZeroAmount);
+    require_gt!(share_supply, 0, FlashLoanError::EmptyPool);
+    mul_div(
+        amount,
+        u128::from(share_supply) + VIRTUAL_SHARES,
+        u128::from(pool_amount) + VIRTUAL_ASSETS,
+        true,
+    )
+}
+
+/// Get the fee charged for borrowing `amount`, including the referral fee
+pub fn loan_fee(amount: u64, loan_fee: u64, referral_fee: u64) -> Result<u64> {
+    require_gt!(amount, 0, FlashLoanError::ZeroAmount);
+    mul_div(
+        amount,
+        u128::from(loan_fee) + u128::from(referral_fee),
+        FEE_DENOMINATOR,
+        false,
+    )
+}
+
+/// Get the minimum amount to repay after borrowing `amount`
+pub fn repayment_amount(amount: u64, loan_fee: u64, referral_fee: u64) -> Result<u64> {
+    amount
+        .checked_add(self::loan_fee(amount, loan_fee, referral_fee)?)
+        .ok_or_else(|| error!(FlashLoanError::MathOverflow))
+}
+
+/// Split a repayment of `amount` into the borrowed amount and the pool and referral fees
+pub fn split_repayment(amount: u64, loan_fee: u64, referral_fee: u64) -> Result<RepaymentSplit> {
+    let principal = mul_div(
+        amount,
+        FEE_DENOMINATOR,
+        FEE_DENOMINATOR + u128::from(loan_fee) + u128::from(referral_fee),
+        false,
+    )?;
+    let referral_fee = mul_div(principal, u128::from(referral_fee), FEE_DENOMINATOR, false)?;
+    let pool_repayment = amount
+        .checked_sub(referral_fee)
+        .ok_or_else(|| error!(FlashLoanError::MathOverflow))?;
+    Ok(RepaymentSplit {
+        principal,
+        pool_repayment,
+        pool_fee: pool_repayment.saturating_sub(principal),
+        referral_fee,
+    })
+}
+
+/// Compute `value * numerator / denominator`, rounding up if `round_up` is set
+fn mul_div(value: u64, numerator: u128, denominator: u128, round_up: bool) -> Result<u64> {
+    let product = u128::from(value)
+        .checked_mul(numerator)
+        .ok_or_else(|| error!(FlashLoanError::MathOverflow))?;
+    let quotient = if round_up {
+        product.div_ceil(denominator)
+    } else {
+        product / denominator
+    };
+    u64::try_from(quotient).map_err(|_| error!(FlashLoanError::MathOverflow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LOAN_FEE, REFERRAL_FEE};
+
+    #[test]
+    fn test_deposit_shares() {
+        assert_eq!(deposit_shares(1_000, 0, 0).unwrap(), 1_000_000);
+        assert_eq!(deposit_shares(3, 2_999, 1_999_000).unwrap(), 2_000);
+        // rounds down
+        assert_eq!(deposit_shares(1, 2_999, 1_999_000).unwrap(), 666);
+        assert_eq!(deposit_shares(1, 1_000_000, 1).unwrap(), 0);
+        assert_eq!(
+            deposit_shares(0, 1_000, 1_000),
+            Err(FlashLoanError::ZeroAmount.into())
+        );
+        assert_eq!(
+            deposit_shares(u64::MAX, 0, u64::MAX),
+            Err(FlashLoanError::MathOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_mint_shares_cost() {
+        assert_eq!(mint_shares_cost(1_000_000, 0, 0).unwrap(), 1_000);
+        assert_eq!(mint_shares_cost(2_000, 2_999, 1_999_000).unwrap(), 3);
+        // rounds up
+        assert_eq!(mint_shares_cost(1, 2_999, 1_999_000).unwrap(), 1);
+        assert_eq!(mint_shares_cost(667, 2_999, 1_999_000).unwrap(), 2);
+        assert_eq!(
+            mint_shares_cost(0, 1_000, 1_000),
+            Err(FlashLoanError::ZeroAmount.into())
+        );
+        assert_eq!(
+            mint_shares_cost(u64::MAX, u64::MAX, 0),
+            Err(FlashLoanError::MathOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_redeem_tokens() {
+        assert_eq!(redeem_tokens(2_000, 2_999, 1_999_000).unwrap(), 3);
+        // rounds down
+        assert_eq!(redeem_tokens(1_999, 2_999, 1_999_000).unwrap(), 2);
+        assert_eq!(redeem_tokens(1_999_000, 0, 1_999_000).unwrap(), 0);
+        assert_eq!(
+            redeem_tokens(0, 1_000, 1_000),
+            Err(FlashLoanError::ZeroAmount.into())
+        );
+        assert_eq!(
+            redeem_tokens(1_000, 1_000, 0),
+            Err(FlashLoanError::EmptyPool.into())
+        );
+        assert_eq!(
+            redeem_tokens(u64::MAX, u64::MAX, 1),
+            Err(FlashLoanError::MathOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_withdraw_shares() {
+        assert_eq!(withdraw_shares(3, 2_999, 1_999_000).unwrap(), 2_000);
+        // rounds up
+        assert_eq!(withdraw_shares(1, 2_999, 1_999_000).unwrap(), 667);
+        assert_eq!(withdraw_shares(4, 2_999, 1_999_000).unwrap(), 2_667);
+        assert_eq!(
+            withdraw_shares(0, 1_000, 1_000),
+            Err(FlashLoanError::ZeroAmount.into())
+        );
+        assert_eq!(
+            withdraw_shares(1_000, 1_000, 0),
+            Err(FlashLoanError::EmptyPool.into())
+        );
+        assert_eq!(
+            withdraw_shares(u64::MAX, 0, u64::MAX),
+            Err(FlashLoanError::MathOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_loan_fee() {
+        assert_eq!(loan_fee(1_000_000, LOAN_FEE, REFERRAL_FEE).unwrap(), 950);
+        assert_eq!(loan_fee(1_000_000, LOAN_FEE, 0).unwrap(), 900);
+        // rounds down
+        assert_eq!(loan_fee(1_000, LOAN_FEE, REFERRAL_FEE).unwrap(), 0);
+        assert_eq!(
+            loan_fee(0, LOAN_FEE, REFERRAL_FEE),
+            Err(FlashLoanError::ZeroAmount.into())
+        );
+        assert_eq!(
+            repayment_amount(1_000_000, LOAN_FEE, REFERRAL_FEE).unwrap(),
+            1_000_950
+        );
+        assert_eq!(
+            repayment_amount(u64::MAX, LOAN_FEE, REFERRAL_FEE),
+            Err(FlashLoanError::MathOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_split_repayment() {
+        assert_eq!(
+            split_repayment(1_000_950, LOAN_FEE, REFERRAL_FEE).unwrap(),
+            RepaymentSplit {
+                principal: 1_000_000,
+                pool_repayment: 1_000_900,
+                pool_fee: 900,
+                referral_fee: 50,
+            }
+        );
+        assert_eq!(
+            split_repayment(1_000_900, LOAN_FEE, 0).unwrap(),
+            RepaymentSplit {
+                principal: 1_000_000,
+                pool_repayment: 1_000_900,
+                pool_fee: 900,
+                referral_fee: 0,
+            }
+        );
+        assert_eq!(
+            split_repayment(0, LOAN_FEE, REFERRAL_FEE).unwrap(),
+            RepaymentSplit {
+                principal: 0,
+                pool_repayment: 0,
+                pool_fee: 0,
+                referral_fee: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,894 @@
+#![allow(dead_code)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::instruction::{Instruction, InstructionError};
+use anchor_lang::solana_program::program::invoke;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::system_instruction;
+use anchor_lang::{Discriminator, Event, Owner, ZeroCopy};
+use flash_loan_mastery::{FlashLoanError, PoolAuthority, EVENT_IX_TAG};
+use flash_loan_mastery_client as client;
+use flash_loan_mastery_test_harness::{invoked_instructions, logged_data, processor};
+use solana_program_test::{BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::{Transaction, TransactionError};
+
+/// A program that forwards its instruction to the program passed as its first account
+pub fn proxy_program_id() -> Pubkey {
+    Pubkey::new_from_array([7; 32])
+}
+
+fn process_proxy_instruction(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *accounts[0].key,
+        accounts: accounts[1..]
+            .iter()
+            .map(|account| AccountMeta {
+                pubkey: *account.key,
+                is_signer: account.is_signer,
+                is_writable: account.is_writable,
+            })
+            .collect(),
+        data: data.to_vec(),
+    };
+    invoke(&instruction, accounts)
+}
+
+/// Wrap `instruction` so that it is called via CPI from the proxy program
+pub fn proxy_ix(instruction: Instruction) -> Instruction {
+    let mut accounts = vec![AccountMeta::new_readonly(instruction.program_id, false)];
+    accounts.extend(instruction.accounts);
+    Instruction {
+        program_id: proxy_program_id(),
+        accounts,
+        data: instruction.data,
+    }
+}
+
+/// A program that calls each instruction of a batch in turn, as built by [`batch_proxy_ix`]
+pub fn batch_proxy_program_id() -> Pubkey {
+    Pubkey::new_from_array([11; 32])
+}
+
+fn process_batch_proxy_instruction(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let (mut accounts_left, mut data_left) = (accounts, data);
+    while let Some((&num_accounts, rest)) = data_left.split_first() {
+        let data_len = usize::from(u16::from_le_bytes(rest[..2].try_into().unwrap()));
+        let (data, rest) = rest[2..].split_at(data_len);
+        let (instruction_accounts, rest_accounts) =
+            accounts_left.split_at(usize::from(num_accounts) + 1);
+        let instruction = Instruction {
+            program_id: *instruction_accounts[0].key,
+            accounts: instruction_accounts[1..]
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: data.to_vec(),
+        };
+        invoke(&instruction, accounts)?;
+        accounts_left = rest_accounts;
+        data_left = rest;
+    }
+    Ok(())
+}
+
+/// Wrap `instructions` so that they are all called via CPI from one instruction of the batch
+/// proxy program
+pub fn batch_proxy_ix(instructions: Vec<Instruction>) -> Instruction {
+    let mut accounts = vec![];
+    let mut data = vec![];
+    for instruction in instructions {
+        data.push(u8::try_from(instruction.accounts.len()).unwrap());
+        data.extend(u16::try_from(instruction.data.len()).unwrap().to_le_bytes());
+        data.extend(instruction.data);
+        accounts.push(AccountMeta::new_readonly(instruction.program_id, false));
+        accounts.extend(instruction.accounts);
+    }
+    Instruction {
+        program_id: batch_proxy_program_id(),
+        accounts,
+        data,
+    }
+}
+
+/// A flash loan receiver that pays the pool the little-endian `u64` in the payload data
+///
+/// Any payload data after that is an instruction the receiver calls first, as built by
+/// [`reentry_payload`].
+pub fn receiver_program_id() -> Pubkey {
+    Pubkey::new_from_array([8; 32])
+}
+
+fn process_receiver_instruction(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let payload = flash_loan_receiver::decode_on_flash_loan(data)?;
+    let repayment = u64::from_le_bytes(payload.data[..8].try_into().unwrap());
+    if let Some((&num_accounts, data)) = payload.data[8..].split_first() {
+        // the called program and its accounts follow the accounts of `on_flash_loan`
+        let accounts = &accounts[5..];
+        let instruction = Instruction {
+            program_id: *accounts[0].key,
+            accounts: accounts[1..=usize::from(num_accounts)]
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: data.to_vec(),
+        };
+        invoke(&instruction, accounts)?;
+    }
+    // the initiator signed for the token account that received the loan
+    let transfer = spl_token::instruction::transfer(
+        &spl_token::ID,
+        accounts[1].key,
+        accounts[2].key,
+        accounts[0].key,
+        &[],
+        repayment,
+    )?;
+    invoke(&transfer, accounts)
+}
+
+/// A flash loan receiver declared with `on_flash_loan_accounts`, it checks its caller and pays
+/// back the loan and the fee
+pub fn checked_receiver_program_id() -> Pubkey {
+    Pubkey::new_from_array([10; 32])
+}
+
+/// The checked receiver, in a module of its own as the macro needs the Anchor `Signer` in scope
+mod checked_receiver {
+    use anchor_lang::prelude::*;
+    use flash_loan_receiver::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    on_flash_loan_accounts! {
+        /// The accounts of the checked receiver
+        pub struct CheckedOnFlashLoan<'info> {}
+    }
+
+    pub fn process_instruction(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        data: &[u8],
+    ) -> Result<()> {
+        let payload = flash_loan_receiver::decode_on_flash_loan(data)?;
+        let accounts = CheckedOnFlashLoan::try_accounts(
+            program_id,
+            &mut &accounts[..],
+            &data[8..],
+            &mut BTreeMap::new(),
+            &mut BTreeSet::new(),
+        )?;
+        accounts.repay(&payload)
+    }
+}
+
+fn process_checked_receiver_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    checked_receiver::process_instruction(program_id, accounts, data).map_err(Into::into)
+}
+
+/// Build the payload data and remaining accounts of a `flash_loan` whose test receiver calls
+/// `instruction` before paying back `repayment`
+pub fn reentry_payload(repayment: u64, instruction: Instruction) -> (Vec<u8>, Vec<AccountMeta>) {
+    let mut data = repayment.to_le_bytes().to_vec();
+    data.push(u8::try_from(instruction.accounts.len()).unwrap());
+    data.extend(instruction.data);
+    let mut accounts = vec![AccountMeta::new_readonly(instruction.program_id, false)];
+    accounts.extend(instruction.accounts);
+    (data, accounts)
+}
+
+/// The programs run natively, the SPL programs bundled with `solana-program-test` crash the BPF
+/// interpreter on recent toolchains
+///
+/// When `BPF_OUT_DIR` is set, as `cargo test-bpf` does, this program runs from its compiled
+/// shared object instead, so that the runtime rather than the harness answers its stack height
+/// and instructions sysvar checks. The other programs only exist as native test programs.
+pub fn program_test() -> ProgramTest {
+    let mut program_test = ProgramTest::new(
+        "flash_loan_mastery",
+        flash_loan_mastery::ID,
+        Some(processor!(flash_loan_mastery::entry)),
+    );
+    program_test.prefer_bpf(false);
+    program_test.add_program(
+        "spl_token",
+        spl_token::ID,
+        Some(processor!(spl_token::processor::Processor::process)),
+    );
+    program_test.add_program(
+        "spl_associated_token_account",
+        spl_associated_token_account::ID,
+        Some(processor!(
+            spl_associated_token_account::processor::process_instruction
+        )),
+    );
+    program_test.add_program(
+        "proxy",
+        proxy_program_id(),
+        Some(processor!(process_proxy_instruction)),
+    );
+    program_test.add_program(
+        "batch_proxy",
+        batch_proxy_program_id(),
+        Some(processor!(process_batch_proxy_instruction)),
+    );
+    program_test.add_program(
+        "receiver",
+        receiver_program_id(),
+        Some(processor!(process_receiver_instruction)),
+    );
+    program_test.add_program(
+        "checked_receiver",
+        checked_receiver_program_id(),
+        Some(processor!(process_checked_receiver_instruction)),
+    );
+    program_test
+}
+
+pub async fn start() -> ProgramTestContext {
+    program_test().start_with_context().await
+}
+
+pub async fn process(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> std::result::Result<(), TransactionError> {
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        context.last_blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|err: BanksClientError| err.unwrap())
+}
+
+pub fn assert_error(
+    result: std::result::Result<(), TransactionError>,
+    instruction_index: u8,
+    error: FlashLoanError,
+) {
+    assert_eq!(
+        result.unwrap_err(),
+        TransactionError::InstructionError(
+            instruction_index,
+            InstructionError::Custom(error.into())
+        )
+    );
+}
+
+/// Send `lamports` from the test payer to `to`
+pub async fn airdrop(context: &mut ProgramTestContext, to: &Pubkey, lamports: u64) {
+    let ix = system_instruction::transfer(&context.payer.pubkey(), to, lamports);
+    process(context, &[ix], &[]).await.unwrap();
+}
+
+pub async fn create_mint(
+    context: &mut ProgramTestContext,
+    authority: &Pubkey,
+    decimals: u8,
+) -> Pubkey {
+    let mint = Keypair::new();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    process(
+        context,
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &mint.pubkey(),
+                rent.minimum_balance(spl_token::state::Mint::LEN),
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::ID,
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::ID,
+                &mint.pubkey(),
+                authority,
+                Some(authority),
+                decimals,
+            )
+            .unwrap(),
+        ],
+        &[&mint],
+    )
+    .await
+    .unwrap();
+    mint.pubkey()
+}
+
+pub async fn create_token_account(
+    context: &mut ProgramTestContext,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Pubkey {
+    let token_account = Keypair::new();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    process(
+        context,
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &token_account.pubkey(),
+                rent.minimum_balance(spl_token::state::Account::LEN),
+                spl_token::state::Account::LEN as u64,
+                &spl_token::ID,
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::ID,
+                &token_account.pubkey(),
+                mint,
+                owner,
+            )
+            .unwrap(),
+        ],
+        &[&token_account],
+    )
+    .await
+    .unwrap();
+    token_account.pubkey()
+}
+
+/// The accounts instructions emitting events need when the `event-cpi` feature is enabled
+pub fn event_account_metas() -> Vec<AccountMeta> {
+    if cfg!(feature = "event-cpi") {
+        client::event_cpi_account_metas()
+    } else {
+        vec![]
+    }
+}
+
+/// Append the accounts `instruction` needs to emit events
+pub fn with_events(mut instruction: Instruction) -> Instruction {
+    instruction.accounts.extend(event_account_metas());
+    instruction
+}
+
+/// Whether this program runs natively rather than from its compiled shared object
+pub fn runs_natively() -> bool {
+    std::env::var("BPF_OUT_DIR").is_err()
+}
+
+/// The events of type `T` matching `filter` emitted so far, oldest first
+///
+/// Events are read from the program logs, or from the self-CPI instructions when the `event-cpi`
+/// feature is enabled. Every test logs to the same place, so `filter` should only match events
+/// of the calling test, e.g. those of its pool. Only the harness records events, so there are
+/// none unless the program [`runs_natively`].
+pub fn emitted_events<T: Event + Discriminator + AnchorDeserialize>(
+    filter: impl Fn(&T) -> bool,
+) -> Vec<T> {
+    let logged = logged_data()
+        .into_iter()
+        .filter_map(|fields| match &fields[..] {
+            [data] => Some(data.clone()),
+            _ => None,
+        });
+    let self_cpis = invoked_instructions()
+        .into_iter()
+        .filter(|instruction| instruction.program_id == flash_loan_mastery::ID)
+        .filter_map(|instruction| {
+            instruction
+                .data
+                .strip_prefix(&EVENT_IX_TAG.to_le_bytes()[..])
+                .map(<[u8]>::to_vec)
+        });
+    logged
+        .chain(self_cpis)
+        .filter_map(|data| {
+            let event = data.strip_prefix(&T::discriminator()[..])?;
+            Some(T::try_from_slice(event).unwrap())
+        })
+        .filter(filter)
+        .collect()
+}
+
+/// Write a zero-copy account of this program directly into the bank
+pub fn set_zero_copy_account<T: ZeroCopy + Owner>(
+    context: &mut ProgramTestContext,
+    address: &Pubkey,
+    state: &T,
+) {
+    let mut data = T::discriminator().to_vec();
+    data.extend_from_slice(bytemuck::bytes_of(state));
+    let mut account = AccountSharedData::new(
+        Rent::default().minimum_balance(data.len()),
+        data.len(),
+        &T::owner(),
+    );
+    account.set_data(data);
+    context.set_account(address, &account);
+}
+
+pub async fn mint_to(context: &mut ProgramTestContext, mint: &Pubkey, to: &Pubkey, amount: u64) {
+    let ix = spl_token::instruction::mint_to(
+        &spl_token::ID,
+        mint,
+        to,
+        &context.payer.pubkey(),
+        &[],
+        amount,
+    )
+    .unwrap();
+    process(context, &[ix], &[]).await.unwrap();
+}
+
+pub async fn token_balance(context: &mut ProgramTestContext, token_account: &Pubkey) -> u64 {
+    let account = context
+        .banks_client
+        .get_account(*token_account)
+        .await
+        .unwrap()
+        .unwrap();
+    spl_token::state::Account::unpack(&account.data)
+        .unwrap()
+        .amount
+}
+
+pub async fn mint_supply(context: &mut ProgramTestContext, mint: &Pubkey) -> u64 {
+    let account = context
+        .banks_client
+        .get_account(*mint)
+        .await
+        .unwrap()
+        .unwrap();
+    spl_token::state::Mint::unpack(&account.data)
+        .unwrap()
+        .supply
+}
+
+pub async fn load_account<T: AccountDeserialize>(
+    context: &mut ProgramTestContext,
+    address: &Pubkey,
+) -> T {
+    let account = context
+        .banks_client
+        .get_account(*address)
+        .await
+        .unwrap()
+        .unwrap();
+    T::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+/// Write the program data account of an upgradeable deployment of this program
+///
+/// The program itself stays a native program, so it doesn't point to this account.
+pub fn set_program_data(context: &mut ProgramTestContext, upgrade_authority: &Pubkey) {
+    let state = UpgradeableLoaderState::ProgramData {
+        slot: 0,
+        upgrade_authority_address: Some(*upgrade_authority),
+    };
+    let account = AccountSharedData::new_data(
+        Rent::default().minimum_balance(UpgradeableLoaderState::programdata_data_offset().unwrap()),
+        &state,
+        &bpf_loader_upgradeable::ID,
+    )
+    .unwrap();
+    let program_data = Pubkey::find_program_address(
+        &[flash_loan_mastery::ID.as_ref()],
+        &bpf_loader_upgradeable::ID,
+    )
+    .0;
+    context.set_account(&program_data, &account);
+}
+
+/// A pool whose admin is the test payer
+#[derive(Debug, Clone, Copy)]
+pub struct Pool {
+    pub mint: Pubkey,
+    pub pool_share_mint: Pubkey,
+    pub pool_authority: Pubkey,
+    pub vault: Pubkey,
+    pub pool_config: Pubkey,
+    pub pool_stats: Pubkey,
+    pub loan_receipt: Pubkey,
+    pub cpi_allowlist: Pubkey,
+}
+
+impl Pool {
+    pub fn new(mint: Pubkey, pool_share_mint: Pubkey) -> Self {
+        let pool_authority = client::find_pool_authority(&mint).0;
+        Self {
+            mint,
+            pool_share_mint,
+            pool_authority,
+            vault: client::pool_vault_address(&mint),
+            pool_config: client::find_pool_config(&pool_authority).0,
+            pool_stats: client::find_pool_stats(&pool_authority).0,
+            loan_receipt: client::find_loan_receipt(&pool_authority).0,
+            cpi_allowlist: client::find_cpi_allowlist(&pool_authority).0,
+        }
+    }
+
+    /// Create the mints, the pool and its vault
+    pub async fn create(context: &mut ProgramTestContext) -> Self {
+        let payer = context.payer.pubkey();
+        let mint = create_mint(context, &payer, 9).await;
+        let pool_share_mint = create_mint(context, &payer, 9).await;
+        let pool = Self::new(mint, pool_share_mint);
+        process(
+            context,
+            &[
+                pool.init_pool_ix(&payer),
+                pool.init_pool_config_ix(&payer),
+                pool.init_pool_stats_ix(&payer),
+                spl_associated_token_account::instruction::create_associated_token_account(
+                    &payer,
+                    &pool.pool_authority,
+                    &mint,
+                ),
+            ],
+            &[],
+        )
+        .await
+        .unwrap();
+        pool
+    }
+
+    /// Shrink the `PoolAuthority` account of the pool back to the layout it had before pools
+    /// had an admin
+    pub fn set_legacy_pool_authority(&self, context: &mut ProgramTestContext) {
+        let mut data = PoolAuthority::discriminator().to_vec();
+        data.extend_from_slice(self.mint.as_ref());
+        data.extend_from_slice(self.pool_share_mint.as_ref());
+        data.push(self.pool_authority_bump());
+        let mut account = AccountSharedData::new(
+            Rent::default().minimum_balance(data.len()),
+            data.len(),
+            &flash_loan_mastery::ID,
+        );
+        account.set_data(data);
+        context.set_account(&self.pool_authority, &account);
+    }
+
+    pub fn pool_authority_bump(&self) -> u8 {
+        client::find_pool_authority(&self.mint).1
+    }
+
+    /// Create the CPI allowlist of the pool, allowing `programs`
+    pub async fn create_cpi_allowlist(
+        &self,
+        context: &mut ProgramTestContext,
+        programs: &[Pubkey],
+    ) {
+        let admin = context.payer.pubkey();
+        let mut instructions = vec![self.init_cpi_allowlist_ix(&admin)];
+        instructions.extend(
+            programs
+                .iter()
+                .map(|program| self.add_cpi_program_ix(&admin, program)),
+        );
+        process(context, &instructions, &[]).await.unwrap();
+    }
+
+    pub fn init_pool_ix(&self, payer: &Pubkey) -> Instruction {
+        with_events(client::init_pool_ix(
+            payer,
+            &self.mint,
+            &self.pool_share_mint,
+            payer,
+            payer,
+        ))
+    }
+
+    pub fn init_pool_config_ix(&self, funder: &Pubkey) -> Instruction {
+        client::init_pool_config_ix(funder, &self.mint)
+    }
+
+    pub fn init_pool_stats_ix(&self, funder: &Pubkey) -> Instruction {
+        client::init_pool_stats_ix(funder, &self.mint)
+    }
+
+    pub fn deposit_ix(
+        &self,
+        depositor: &Pubkey,
+        token_from: &Pubkey,
+        pool_share_token_to: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        with_events(client::deposit_ix(
+            depositor,
+            &self.mint,
+            &self.pool_share_mint,
+            token_from,
+            pool_share_token_to,
+            amount,
+        ))
+    }
+
+    pub fn mint_shares_ix(
+        &self,
+        depositor: &Pubkey,
+        token_from: &Pubkey,
+        pool_share_token_to: &Pubkey,
+        share_amount: u64,
+        max_tokens: u64,
+    ) -> Instruction {
+        with_events(client::mint_shares_ix(
+            depositor,
+            &self.mint,
+            &self.pool_share_mint,
+            token_from,
+            pool_share_token_to,
+            share_amount,
+            max_tokens,
+        ))
+    }
+
+    pub fn withdraw_ix(
+        &self,
+        withdrawer: &Pubkey,
+        token_to: &Pubkey,
+        pool_share_token_from: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        with_events(client::withdraw_ix(
+            withdrawer,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            pool_share_token_from,
+            amount,
+        ))
+    }
+
+    pub fn deposit_with_min_shares_ix(
+        &self,
+        depositor: &Pubkey,
+        token_from: &Pubkey,
+        pool_share_token_to: &Pubkey,
+        amount: u64,
+        min_shares: u64,
+    ) -> Instruction {
+        with_events(client::deposit_with_min_shares_ix(
+            depositor,
+            &self.mint,
+            &self.pool_share_mint,
+            token_from,
+            pool_share_token_to,
+            amount,
+            min_shares,
+        ))
+    }
+
+    pub fn withdraw_with_min_tokens_ix(
+        &self,
+        withdrawer: &Pubkey,
+        token_to: &Pubkey,
+        pool_share_token_from: &Pubkey,
+        amount: u64,
+        min_tokens: u64,
+    ) -> Instruction {
+        with_events(client::withdraw_with_min_tokens_ix(
+            withdrawer,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            pool_share_token_from,
+            amount,
+            min_tokens,
+        ))
+    }
+
+    pub fn withdraw_exact_tokens_ix(
+        &self,
+        withdrawer: &Pubkey,
+        token_to: &Pubkey,
+        pool_share_token_from: &Pubkey,
+        token_amount: u64,
+        max_shares: u64,
+    ) -> Instruction {
+        with_events(client::withdraw_exact_tokens_ix(
+            withdrawer,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            pool_share_token_from,
+            token_amount,
+            max_shares,
+        ))
+    }
+
+    pub fn borrow_ix(&self, borrower: &Pubkey, token_to: &Pubkey, amount: u64) -> Instruction {
+        with_events(client::borrow_ix(borrower, &self.mint, token_to, amount))
+    }
+
+    pub fn borrow_tranche_ix(
+        &self,
+        borrower: &Pubkey,
+        token_to: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        with_events(client::borrow_tranche_ix(
+            borrower, &self.mint, token_to, amount,
+        ))
+    }
+
+    pub fn borrow_with_receipt_ix(
+        &self,
+        borrower: &Pubkey,
+        token_to: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        with_events(client::borrow_with_receipt_ix(
+            borrower,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            amount,
+        ))
+    }
+
+    pub fn settle_ix(&self, borrower: &Pubkey) -> Instruction {
+        with_events(client::settle_ix(
+            borrower,
+            &self.mint,
+            &self.pool_share_mint,
+        ))
+    }
+
+    /// Build a `flash_loan` instruction, the test receiver pays back `repayment`
+    pub fn flash_loan_ix(
+        &self,
+        borrower: &Pubkey,
+        token_to: &Pubkey,
+        amount: u64,
+        repayment: u64,
+    ) -> Instruction {
+        with_events(client::flash_loan_ix(
+            borrower,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            &receiver_program_id(),
+            amount,
+            repayment.to_le_bytes().to_vec(),
+        ))
+    }
+
+    /// Build a `flash_loan` instruction lending to the checked receiver
+    pub fn checked_flash_loan_ix(
+        &self,
+        borrower: &Pubkey,
+        token_to: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        with_events(client::flash_loan_ix(
+            borrower,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            &checked_receiver_program_id(),
+            amount,
+            vec![],
+        ))
+    }
+
+    /// Build a `flash_loan` instruction whose test receiver calls `instruction` with the loan
+    pub fn reentrant_flash_loan_ix(
+        &self,
+        borrower: &Pubkey,
+        token_to: &Pubkey,
+        amount: u64,
+        repayment: u64,
+        instruction: Instruction,
+    ) -> Instruction {
+        let (data, accounts) = reentry_payload(repayment, instruction);
+        let mut flash_loan = client::flash_loan_ix(
+            borrower,
+            &self.mint,
+            &self.pool_share_mint,
+            token_to,
+            &receiver_program_id(),
+            amount,
+            data,
+        );
+        flash_loan.accounts.extend(accounts);
+        with_events(flash_loan)
+    }
+
+    pub fn repay_ix(
+        &self,
+        repayer: &Pubkey,
+        token_from: &Pubkey,
+        amount: u64,
+        referral_token_to: Option<&Pubkey>,
+    ) -> Instruction {
+        with_events(client::repay_ix(
+            repayer,
+            &self.mint,
+            &self.pool_share_mint,
+            token_from,
+            amount,
+            referral_token_to,
+        ))
+    }
+
+    pub fn set_pool_config_ix(
+        &self,
+        admin: &Pubkey,
+        loan_fee: u64,
+        referral_fee: u64,
+    ) -> Instruction {
+        client::set_pool_config_ix(admin, &self.mint, loan_fee, referral_fee)
+    }
+
+    pub fn init_cpi_allowlist_ix(&self, funder: &Pubkey) -> Instruction {
+        client::init_cpi_allowlist_ix(funder, &self.mint)
+    }
+
+    pub fn add_cpi_program_ix(&self, admin: &Pubkey, program: &Pubkey) -> Instruction {
+        client::add_cpi_program_ix(admin, &self.mint, program)
+    }
+
+    pub fn remove_cpi_program_ix(&self, admin: &Pubkey, program: &Pubkey) -> Instruction {
+        client::remove_cpi_program_ix(admin, &self.mint, program)
+    }
+
+    /// Append the CPI allowlist of the pool to `instruction`
+    pub fn with_cpi_allowlist(&self, mut instruction: Instruction) -> Instruction {
+        instruction
+            .accounts
+            .push(AccountMeta::new_readonly(self.cpi_allowlist, false));
+        instruction
+    }
+
+    pub fn set_guardian_ix(&self, admin: &Pubkey, guardian: &Pubkey) -> Instruction {
+        client::set_guardian_ix(admin, &self.mint, guardian)
+    }
+
+    pub fn pause_ix(&self, guardian: &Pubkey) -> Instruction {
+        client::pause_ix(guardian, &self.mint)
+    }
+
+    pub fn unpause_ix(&self, admin: &Pubkey) -> Instruction {
+        client::unpause_ix(admin, &self.mint)
+    }
+
+    pub fn migrate_pool_authority_ix(&self, funder: &Pubkey) -> Instruction {
+        client::migrate_pool_authority_ix(funder, &self.mint)
+    }
+
+    pub fn init_pool_admin_ix(&self, upgrade_authority: &Pubkey, admin: &Pubkey) -> Instruction {
+        client::init_pool_admin_ix(upgrade_authority, &self.mint, admin)
+    }
+
+    pub fn propose_admin_ix(&self, admin: &Pubkey, new_admin: &Pubkey) -> Instruction {
+        client::propose_admin_ix(admin, &self.mint, new_admin)
+    }
+
+    pub fn accept_admin_ix(&self, pending_admin: &Pubkey) -> Instruction {
+        client::accept_admin_ix(pending_admin, &self.mint)
+    }
+}
@@ -0,0 +1,2052 @@
+mod common;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::InstructionError;
+use common::*;
+use flash_loan_mastery::{
+    Borrowed, CpiAllowlist, Deposited, FlashLoanError, LoanReceipt, PoolAuthority, PoolConfig,
+    PoolStats, Repaid, Withdrawn, FEE_DENOMINATOR, LOAN_FEE, LOAN_RECEIPT_SEED, MAX_CPI_PROGRAMS,
+    REFERRAL_FEE,
+};
+use flash_loan_receiver::{FlashLoanPayload, ReceiverError};
+use solana_program_test::{tokio, ProgramTestContext};
+use solana_sdk::native_token::LAMPORTS_PER_SOL;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::TransactionError;
+
+/// A user holding tokens and pool shares
+struct User {
+    keypair: Keypair,
+    token_account: Pubkey,
+    share_account: Pubkey,
+}
+
+impl User {
+    async fn create(context: &mut ProgramTestContext, pool: &Pool, amount: u64) -> Self {
+        let keypair = Keypair::new();
+        // users pay for the accounts they create, like loan receipts
+        airdrop(context, &keypair.pubkey(), LAMPORTS_PER_SOL).await;
+        let token_account = create_token_account(context, &pool.mint, &keypair.pubkey()).await;
+        let share_account =
+            create_token_account(context, &pool.pool_share_mint, &keypair.pubkey()).await;
+        if amount > 0 {
+            mint_to(context, &pool.mint, &token_account, amount).await;
+        }
+        Self {
+            keypair,
+            token_account,
+            share_account,
+        }
+    }
+
+    fn key(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+}
+
+fn fee(amount: u64, fee: u64) -> u64 {
+    u64::try_from(u128::from(amount) * u128::from(fee) / FEE_DENOMINATOR).unwrap()
+}
+
+async fn deposit(context: &mut ProgramTestContext, pool: &Pool, user: &User, amount: u64) {
+    let ix = pool.deposit_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        amount,
+    );
+    process(context, &[ix], &[&user.keypair]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_deposit_and_withdraw() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user1 = User::create(&mut context, &pool, 100_000).await;
+    let user2 = User::create(&mut context, &pool, 100_000).await;
+    let user3 = User::create(&mut context, &pool, 1_000_000).await;
+
+    // the first deposit gets a thousand shares per token
+    deposit(&mut context, &pool, &user1, 100_000).await;
+    assert_eq!(token_balance(&mut context, &pool.vault).await, 100_000);
+    assert_eq!(
+        token_balance(&mut context, &user1.share_account).await,
+        100_000_000
+    );
+    assert_eq!(
+        mint_supply(&mut context, &pool.pool_share_mint).await,
+        100_000_000
+    );
+
+    // an equal deposit gets an equal share of the pool
+    deposit(&mut context, &pool, &user2, 100_000).await;
+    assert_eq!(token_balance(&mut context, &pool.vault).await, 200_000);
+    assert_eq!(
+        token_balance(&mut context, &user2.share_account).await,
+        100_000_000
+    );
+    assert_eq!(
+        mint_supply(&mut context, &pool.pool_share_mint).await,
+        200_000_000
+    );
+
+    // simulate pool profits, later deposits get fewer shares per token
+    mint_to(&mut context, &pool.mint, &pool.vault, 234_567).await;
+    deposit(&mut context, &pool, &user3, 33_000).await;
+    let user3_shares = 33_000 * (200_000_000 + 1_000) / (200_000 + 234_567 + 1);
+    assert_eq!(
+        token_balance(&mut context, &user3.share_account).await,
+        user3_shares
+    );
+    assert_eq!(
+        mint_supply(&mut context, &pool.pool_share_mint).await,
+        200_000_000 + user3_shares
+    );
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        200_000 + 234_567 + 33_000
+    );
+
+    // withdrawing redeems shares for their part of the pool
+    let vault_before = token_balance(&mut context, &pool.vault).await;
+    let supply_before = mint_supply(&mut context, &pool.pool_share_mint).await;
+    let ix = pool.withdraw_ix(
+        &user1.key(),
+        &user1.token_account,
+        &user1.share_account,
+        50_000,
+    );
+    process(&mut context, &[ix], &[&user1.keypair])
+        .await
+        .unwrap();
+    let token_value = 50_000 * (vault_before + 1) / (supply_before + 1_000);
+    assert_eq!(
+        token_balance(&mut context, &user1.share_account).await,
+        100_000_000 - 50_000
+    );
+    assert_eq!(
+        mint_supply(&mut context, &pool.pool_share_mint).await,
+        supply_before - 50_000
+    );
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        vault_before - token_value
+    );
+    assert_eq!(
+        token_balance(&mut context, &user1.token_account).await,
+        token_value
+    );
+
+    // events report the amounts moved and the pool after each change
+    if !runs_natively() {
+        return;
+    }
+    let deposited = emitted_events(|event: &Deposited| event.pool_authority == pool.pool_authority);
+    assert_eq!(
+        deposited,
+        [
+            Deposited {
+                pool_authority: pool.pool_authority,
+                depositor: user1.key(),
+                amount: 100_000,
+                share_amount: 100_000_000,
+                pool_amount: 100_000,
+                pool_share_supply: 100_000_000,
+            },
+            Deposited {
+                pool_authority: pool.pool_authority,
+                depositor: user2.key(),
+                amount: 100_000,
+                share_amount: 100_000_000,
+                pool_amount: 200_000,
+                pool_share_supply: 200_000_000,
+            },
+            Deposited {
+                pool_authority: pool.pool_authority,
+                depositor: user3.key(),
+                amount: 33_000,
+                share_amount: user3_shares,
+                pool_amount: vault_before,
+                pool_share_supply: supply_before,
+            },
+        ]
+    );
+    let withdrawn = emitted_events(|event: &Withdrawn| event.pool_authority == pool.pool_authority);
+    assert_eq!(
+        withdrawn,
+        [Withdrawn {
+            pool_authority: pool.pool_authority,
+            withdrawer: user1.key(),
+            amount: token_value,
+            share_amount: 50_000,
+            pool_amount: vault_before - token_value,
+            pool_share_supply: supply_before - 50_000,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_donation_attack() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let attacker = User::create(&mut context, &pool, 1_000_001).await;
+    let victim = User::create(&mut context, &pool, 600_000).await;
+
+    // the attacker deposits 1 token then inflates the share price with a donation
+    deposit(&mut context, &pool, &attacker, 1).await;
+    assert_eq!(
+        token_balance(&mut context, &attacker.share_account).await,
+        1_000
+    );
+    let ix = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &attacker.token_account,
+        &pool.vault,
+        &attacker.key(),
+        &[],
+        1_000_000,
+    )
+    .unwrap();
+    process(&mut context, &[ix], &[&attacker.keypair])
+        .await
+        .unwrap();
+
+    // deposits that would round down to nothing are rejected
+    let ix = pool.deposit_ix(
+        &victim.key(),
+        &victim.token_account,
+        &victim.share_account,
+        500,
+    );
+    assert_error(
+        process(&mut context, &[ix], &[&victim.keypair]).await,
+        0,
+        FlashLoanError::ZeroSharesMinted,
+    );
+
+    // the virtual shares capture most of the donation: the victim only loses to rounding while
+    // the attacker loses about half of their donation
+    deposit(&mut context, &pool, &victim, 600_000).await;
+    let victim_shares = token_balance(&mut context, &victim.share_account).await;
+    assert_eq!(victim_shares, 1_199);
+    let ix = pool.withdraw_ix(
+        &attacker.key(),
+        &attacker.token_account,
+        &attacker.share_account,
+        1_000,
+    );
+    process(&mut context, &[ix], &[&attacker.keypair])
+        .await
+        .unwrap();
+    let ix = pool.withdraw_ix(
+        &victim.key(),
+        &victim.token_account,
+        &victim.share_account,
+        victim_shares,
+    );
+    process(&mut context, &[ix], &[&victim.keypair])
+        .await
+        .unwrap();
+    let attacker_loss = 1_000_001 - token_balance(&mut context, &attacker.token_account).await;
+    let victim_loss = 600_000 - token_balance(&mut context, &victim.token_account).await;
+    assert_eq!(victim_loss, 312);
+    assert!(victim_loss < 600_000 / 1_000);
+    assert!(attacker_loss > 1_000 * victim_loss);
+}
+
+#[tokio::test]
+async fn test_slippage() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &user, 100_000).await;
+
+    // a donation front-running the deposit halves the shares it mints
+    mint_to(&mut context, &pool.mint, &pool.vault, 100_000).await;
+    let ix = pool.deposit_with_min_shares_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        100_000,
+        100_000_000,
+    );
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::SlippageExceeded,
+    );
+    let ix = pool.deposit_with_min_shares_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        100_000,
+        50_000_000,
+    );
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &user.share_account).await,
+        150_000_249
+    );
+
+    // 1_000_000 shares are worth 1_999 tokens
+    let ix = pool.withdraw_with_min_tokens_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        1_000_000,
+        2_000,
+    );
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::SlippageExceeded,
+    );
+    let ix = pool.withdraw_with_min_tokens_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        1_000_000,
+        1_999,
+    );
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &user.token_account).await,
+        1_000_000 - 200_000 + 1_999
+    );
+}
+
+#[tokio::test]
+async fn test_mint_shares() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &user, 100_000).await;
+    mint_to(&mut context, &pool.mint, &pool.vault, 100_000).await;
+
+    // 1_999 tokens only mint 999_504 shares, so 1_000_000 shares cost 2_000 tokens
+    let ix = pool.mint_shares_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        1_000_000,
+        1_999,
+    );
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::SlippageExceeded,
+    );
+    let ix = pool.mint_shares_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        1_000_000,
+        2_000,
+    );
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &user.token_account).await,
+        1_000_000 - 100_000 - 2_000
+    );
+    assert_eq!(
+        token_balance(&mut context, &user.share_account).await,
+        100_000_000 + 1_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        200_000 + 2_000
+    );
+
+    let ix = pool.mint_shares_ix(&user.key(), &user.token_account, &user.share_account, 0, 1);
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::ZeroAmount,
+    );
+}
+
+#[tokio::test]
+async fn test_withdraw_exact_tokens() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &user, 100_000).await;
+    mint_to(&mut context, &pool.mint, &pool.vault, 100_000).await;
+
+    // 1_000_000 shares are only worth 1_999 tokens, so 2_000 tokens cost 1_000_005 shares
+    let ix = pool.withdraw_exact_tokens_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        2_000,
+        1_000_004,
+    );
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::SlippageExceeded,
+    );
+    let ix = pool.withdraw_exact_tokens_ix(
+        &user.key(),
+        &user.token_account,
+        &user.share_account,
+        2_000,
+        1_000_005,
+    );
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &user.token_account).await,
+        1_000_000 - 100_000 + 2_000
+    );
+    assert_eq!(
+        token_balance(&mut context, &user.share_account).await,
+        100_000_000 - 1_000_005
+    );
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        200_000 - 2_000
+    );
+    assert_eq!(
+        mint_supply(&mut context, &pool.pool_share_mint).await,
+        100_000_000 - 1_000_005
+    );
+}
+
+#[tokio::test]
+async fn test_deposit_owner_mismatch() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user = User::create(&mut context, &pool, 1_000).await;
+    let other = User::create(&mut context, &pool, 0).await;
+
+    // the deposit must go to a token account owned by the pool
+    let mut ix = pool.deposit_ix(&user.key(), &user.token_account, &user.share_account, 1_000);
+    ix.accounts[2].pubkey = other.token_account;
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::OwnerMismatch,
+    );
+}
+
+#[cfg(feature = "event-cpi")]
+#[tokio::test]
+async fn test_missing_event_accounts() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let user = User::create(&mut context, &pool, 1_000).await;
+
+    let mut ix = pool.deposit_ix(&user.key(), &user.token_account, &user.share_account, 1_000);
+    let event_accounts = ix.accounts.split_off(ix.accounts.len() - 2);
+    assert_error(
+        process(&mut context, &[ix.clone()], &[&user.keypair]).await,
+        0,
+        FlashLoanError::MissingEventAccounts,
+    );
+
+    ix.accounts.extend(event_accounts);
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_borrow_and_repay() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+    let referrer = User::create(&mut context, &pool, 0).await;
+
+    let amount = 400_000;
+    let loan_fee = fee(amount, LOAN_FEE);
+    let referral_fee = fee(amount, REFERRAL_FEE);
+    let repayment = amount + fee(amount, LOAN_FEE + REFERRAL_FEE);
+
+    // without a referral the referral fee isn't charged
+    process(
+        &mut context,
+        &[
+            pool.borrow_ix(&borrower.key(), &borrower.token_account, amount),
+            pool.repay_ix(&borrower.key(), &borrower.token_account, repayment, None),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + loan_fee
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - loan_fee
+    );
+
+    // with a referral the referral fee goes to the referrer
+    process(
+        &mut context,
+        &[
+            pool.borrow_ix(&borrower.key(), &borrower.token_account, amount),
+            pool.repay_ix(
+                &borrower.key(),
+                &borrower.token_account,
+                repayment,
+                Some(&referrer.token_account),
+            ),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + loan_fee * 2
+    );
+    assert_eq!(
+        token_balance(&mut context, &referrer.token_account).await,
+        referral_fee
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - loan_fee * 2 - referral_fee
+    );
+
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!({ pool_stats.total_loans }, 2);
+    assert_eq!({ pool_stats.total_borrowed }, u128::from(amount * 2));
+    assert_eq!({ pool_stats.total_fees }, u128::from(loan_fee * 2));
+    assert_eq!({ pool_stats.total_referral_fees }, u128::from(referral_fee));
+
+    if !runs_natively() {
+        return;
+    }
+    let borrowed = || Borrowed {
+        pool_authority: pool.pool_authority,
+        borrower: borrower.key(),
+        token_to: borrower.token_account,
+        amount,
+        fee: repayment - amount,
+    };
+    assert_eq!(
+        emitted_events(|event: &Borrowed| event.pool_authority == pool.pool_authority),
+        [borrowed(), borrowed()]
+    );
+    let supply = mint_supply(&mut context, &pool.pool_share_mint).await;
+    assert_eq!(
+        emitted_events(|event: &Repaid| event.pool_authority == pool.pool_authority),
+        [
+            Repaid {
+                pool_authority: pool.pool_authority,
+                repayer: borrower.key(),
+                amount: amount + loan_fee,
+                fee: loan_fee,
+                referral_fee: 0,
+                referrer: None,
+                pool_amount: 1_000_000 + loan_fee,
+                pool_share_supply: supply,
+            },
+            Repaid {
+                pool_authority: pool.pool_authority,
+                repayer: borrower.key(),
+                amount: repayment,
+                fee: loan_fee,
+                referral_fee,
+                referrer: Some(referrer.token_account),
+                pool_amount: 1_000_000 + loan_fee * 2,
+                pool_share_supply: supply,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_borrow_errors() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let other_pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+    let borrow = |amount| pool.borrow_ix(&borrower.key(), &borrower.token_account, amount);
+    let repay = |amount| pool.repay_ix(&borrower.key(), &borrower.token_account, amount, None);
+
+    // borrowing without repaying
+    assert_error(
+        process(&mut context, &[borrow(100_000)], &[&borrower.keypair]).await,
+        0,
+        FlashLoanError::NoRepaymentInstructionFound,
+    );
+
+    // borrowing again before repaying
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), borrow(10), repay(200_000)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+
+    // depositing the loan before repaying it, which would mint shares priced off the drained
+    // vault
+    let deposit_loan = pool.deposit_ix(
+        &borrower.key(),
+        &borrower.token_account,
+        &borrower.share_account,
+        500_000,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(500_000), deposit_loan, repay(510_000)],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::LoanOutstanding,
+    );
+
+    // repaying less than what was borrowed
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), repay(90_000)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    // repaying less than the fees
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), repay(100_000)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    // repaying to another token account
+    let mut wrong_vault = repay(200_000);
+    wrong_vault.accounts[2].pubkey = borrower.token_account;
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), wrong_vault],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::AddressMismatch,
+    );
+
+    // repaying to another pool
+    let mut wrong_pool = repay(200_000);
+    wrong_pool.accounts[3].pubkey = other_pool.pool_authority;
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), wrong_pool],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::PoolMismatch,
+    );
+
+    // nothing was lent
+    assert_eq!(token_balance(&mut context, &pool.vault).await, 1_000_000);
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000
+    );
+}
+
+#[tokio::test]
+async fn test_concurrent_loans() {
+    let mut context = start().await;
+    let usdc = Pool::create(&mut context).await;
+    let sol = Pool::create(&mut context).await;
+    let usdc_lender = User::create(&mut context, &usdc, 1_000_000).await;
+    deposit(&mut context, &usdc, &usdc_lender, 1_000_000).await;
+    let sol_lender = User::create(&mut context, &sol, 1_000_000).await;
+    deposit(&mut context, &sol, &sol_lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &usdc, 10_000).await;
+    let sol_account = create_token_account(&mut context, &sol.mint, &borrower.key()).await;
+    mint_to(&mut context, &sol.mint, &sol_account, 10_000).await;
+
+    let repayment = |amount| amount + fee(amount, LOAN_FEE + REFERRAL_FEE);
+    let borrow_usdc = |amount| usdc.borrow_ix(&borrower.key(), &borrower.token_account, amount);
+    let repay_usdc = |amount| {
+        usdc.repay_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            repayment(amount),
+            None,
+        )
+    };
+    let borrow_sol = |amount| sol.borrow_ix(&borrower.key(), &sol_account, amount);
+    let repay_sol = |amount| sol.repay_ix(&borrower.key(), &sol_account, repayment(amount), None);
+
+    // nested loans
+    process(
+        &mut context,
+        &[
+            borrow_usdc(100_000),
+            borrow_sol(200_000),
+            repay_sol(200_000),
+            repay_usdc(100_000),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+
+    // interleaved loans
+    process(
+        &mut context,
+        &[
+            borrow_usdc(300_000),
+            borrow_sol(400_000),
+            repay_usdc(300_000),
+            repay_sol(400_000),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, &usdc.vault).await,
+        1_000_000 + fee(100_000, LOAN_FEE) + fee(300_000, LOAN_FEE)
+    );
+    assert_eq!(
+        token_balance(&mut context, &sol.vault).await,
+        1_000_000 + fee(200_000, LOAN_FEE) + fee(400_000, LOAN_FEE)
+    );
+
+    // borrowing from the same pool again before repaying is still not allowed
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow_usdc(100_000),
+                borrow_sol(200_000),
+                borrow_usdc(10),
+                repay_sol(200_000),
+                repay_usdc(100_010),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+}
+
+#[tokio::test]
+async fn test_loan_tranches() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+
+    let repayment = |amount| amount + fee(amount, LOAN_FEE + REFERRAL_FEE);
+    let borrow = |amount| pool.borrow_ix(&borrower.key(), &borrower.token_account, amount);
+    let tranche = |amount| pool.borrow_tranche_ix(&borrower.key(), &borrower.token_account, amount);
+    let repay = |amount| pool.repay_ix(&borrower.key(), &borrower.token_account, amount, None);
+
+    // a single repay must cover every tranche
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(100_000),
+                tranche(200_000),
+                tranche(300_000),
+                repay(repayment(100_000) + repayment(200_000)),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+    process(
+        &mut context,
+        &[
+            borrow(100_000),
+            tranche(200_000),
+            tranche(300_000),
+            repay(repayment(100_000) + repayment(200_000) + repayment(300_000)),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(600_000, LOAN_FEE)
+    );
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!({ pool_stats.total_loans }, 3);
+    assert_eq!({ pool_stats.total_borrowed }, 600_000);
+
+    // a tranche still has to be repaid
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), repay(repayment(100_000)), tranche(1_000)],
+            &[&borrower.keypair],
+        )
+        .await,
+        2,
+        FlashLoanError::NoRepaymentInstructionFound,
+    );
+}
+
+/// Transfer `amount` tokens from `user` to `to` without going through the program
+fn transfer_ix(
+    user: &User,
+    to: &Pubkey,
+    amount: u64,
+) -> anchor_lang::solana_program::instruction::Instruction {
+    spl_token::instruction::transfer(
+        &spl_token::ID,
+        &user.token_account,
+        to,
+        &user.key(),
+        &[],
+        amount,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_settle() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+
+    // write the receipt `borrow_with_receipt` would give, to check `settle` on its own
+    let loan_receipt = |pool_share_supply| LoanReceipt {
+        pool_authority: pool.pool_authority,
+        borrower: borrower.key(),
+        amount: 100_000,
+        pre_balance: 1_000_000,
+        fee: fee(100_000, LOAN_FEE),
+        pool_share_supply,
+        bump: Pubkey::find_program_address(
+            &[LOAN_RECEIPT_SEED, pool.pool_authority.as_ref()],
+            &flash_loan_mastery::ID,
+        )
+        .1,
+    };
+    set_zero_copy_account(
+        &mut context,
+        &pool.loan_receipt,
+        &loan_receipt(1_000_000_000),
+    );
+
+    // the vault must have grown by the fee
+    assert_error(
+        process(&mut context, &[pool.settle_ix(&borrower.key())], &[]).await,
+        0,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                transfer_ix(&borrower, &pool.vault, fee(100_000, LOAN_FEE) - 1),
+                pool.settle_ix(&borrower.key()),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    // the rent of the receipt goes back to the borrower
+    assert_error(
+        process(
+            &mut context,
+            &[
+                transfer_ix(&borrower, &pool.vault, fee(100_000, LOAN_FEE)),
+                pool.settle_ix(&lender.key()),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::AddressMismatch,
+    );
+
+    // the fee can be paid by any route, here a plain token transfer
+    process(
+        &mut context,
+        &[
+            transfer_ix(&borrower, &pool.vault, fee(100_000, LOAN_FEE) + 1),
+            pool.settle_ix(&borrower.key()),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(pool.loan_receipt)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(context
+        .banks_client
+        .get_account(borrower.key())
+        .await
+        .unwrap()
+        .is_some());
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!(
+        { pool_stats.total_fees },
+        u128::from(fee(100_000, LOAN_FEE) + 1)
+    );
+
+    // deposits would be priced off the vault while it is short of the loan
+    set_zero_copy_account(
+        &mut context,
+        &pool.loan_receipt,
+        &loan_receipt(1_000_000_000),
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                pool.deposit_ix(
+                    &borrower.key(),
+                    &borrower.token_account,
+                    &borrower.share_account,
+                    1_000,
+                ),
+                pool.settle_ix(&borrower.key()),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::PoolSharesChanged,
+    );
+}
+
+#[tokio::test]
+async fn test_borrow_with_receipt() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+
+    let borrow =
+        |amount| pool.borrow_with_receipt_ix(&borrower.key(), &borrower.token_account, amount);
+    let repay = |amount| transfer_ix(&borrower, &pool.vault, amount);
+    let settle = pool.settle_ix(&borrower.key());
+
+    // the loan must be settled
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), repay(100_000 + fee(100_000, LOAN_FEE))],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::NoRepaymentInstructionFound,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[borrow(100_000), repay(100_000), settle.clone()],
+            &[&borrower.keypair],
+        )
+        .await,
+        2,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    // depositing the loan back would mint shares at a discount
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(100_000),
+                pool.deposit_ix(
+                    &borrower.key(),
+                    &borrower.token_account,
+                    &borrower.share_account,
+                    100_000 + fee(100_000, LOAN_FEE),
+                ),
+                settle.clone(),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::LoanOutstanding,
+    );
+
+    // the settle counts every fee paid into the vault, so no other loan of the pool can come
+    // before it
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(100_000),
+                borrow(50_000),
+                repay(150_000 + fee(150_000, LOAN_FEE)),
+                settle.clone(),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::LoanOutstanding,
+    );
+    let plain_borrow = pool.borrow_ix(&borrower.key(), &borrower.token_account, 500_000);
+    let plain_repay = pool.repay_ix(&borrower.key(), &borrower.token_account, 500_450, None);
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(400_000),
+                plain_borrow.clone(),
+                plain_repay.clone(),
+                settle.clone(),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::LoanOutstanding,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(400_000),
+                pool.flash_loan_ix(&borrower.key(), &borrower.token_account, 500_000, 500_450),
+                settle.clone(),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::LoanOutstanding,
+    );
+
+    // nor can a receipt loan be taken between a borrow and its repay, where the fee of the
+    // borrow would count towards the receipt
+    assert_error(
+        process(
+            &mut context,
+            &[plain_borrow, borrow(400_000), plain_repay, settle.clone()],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+
+    // the vault must get back the whole fee
+    assert_error(
+        process(
+            &mut context,
+            &[
+                borrow(150_000),
+                repay(150_000 + fee(150_000, LOAN_FEE) - 1),
+                settle.clone(),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        2,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    process(
+        &mut context,
+        &[
+            borrow(200_000),
+            repay(200_000 + fee(200_000, LOAN_FEE)),
+            settle,
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(200_000, LOAN_FEE)
+    );
+    assert!(context
+        .banks_client
+        .get_account(pool.loan_receipt)
+        .await
+        .unwrap()
+        .is_none());
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.loan_outstanding, 0);
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!({ pool_stats.total_loans }, 1);
+    assert_eq!(
+        { pool_stats.total_fees },
+        u128::from(fee(200_000, LOAN_FEE))
+    );
+}
+
+#[tokio::test]
+async fn test_flash_loan() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+
+    let flash_loan = |amount, repayment| {
+        pool.flash_loan_ix(&borrower.key(), &borrower.token_account, amount, repayment)
+    };
+
+    // the receiver must pay back the loan and the fee
+    assert_error(
+        process(
+            &mut context,
+            &[flash_loan(100_000, 100_000 + fee(100_000, LOAN_FEE) - 1)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::IncorrectRepaymentAmount,
+    );
+
+    process(
+        &mut context,
+        &[flash_loan(100_000, 100_000 + fee(100_000, LOAN_FEE))],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(100_000, LOAN_FEE)
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - fee(100_000, LOAN_FEE)
+    );
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!({ pool_stats.total_loans }, 1);
+    assert_eq!({ pool_stats.total_borrowed }, 100_000);
+    assert_eq!(
+        { pool_stats.total_fees },
+        u128::from(fee(100_000, LOAN_FEE))
+    );
+
+    // other programs can take flash loans
+    process(
+        &mut context,
+        &[proxy_ix(flash_loan(
+            200_000,
+            200_000 + fee(200_000, LOAN_FEE),
+        ))],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(100_000, LOAN_FEE) + fee(200_000, LOAN_FEE)
+    );
+}
+
+#[tokio::test]
+async fn test_flash_loan_reentry() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let other_pool = Pool::create(&mut context).await;
+    let other_lender = User::create(&mut context, &other_pool, 1_000_000).await;
+    deposit(&mut context, &other_pool, &other_lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+    let other_token_account =
+        create_token_account(&mut context, &other_pool.mint, &borrower.key()).await;
+
+    // the receiver calls `instruction` while it holds the loan
+    let reentrant_flash_loan = |instruction| {
+        pool.reentrant_flash_loan_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            100_000,
+            100_000 + fee(100_000, LOAN_FEE),
+            instruction,
+        )
+    };
+
+    // the runtime rejects calls back into the program while the receiver holds the loan, so it
+    // can't borrow from the pool again or deposit the loan into it
+    for instruction in [
+        pool.borrow_ix(&borrower.key(), &borrower.token_account, 100_000),
+        pool.borrow_with_receipt_ix(&borrower.key(), &borrower.token_account, 100_000),
+        pool.flash_loan_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            100_000,
+            100_000 + fee(100_000, LOAN_FEE),
+        ),
+        pool.deposit_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            &borrower.share_account,
+            100_000,
+        ),
+        other_pool.borrow_ix(&borrower.key(), &other_token_account, 100_000),
+    ] {
+        assert_eq!(
+            process(
+                &mut context,
+                &[reentrant_flash_loan(instruction)],
+                &[&borrower.keypair],
+            )
+            .await
+            .unwrap_err(),
+            TransactionError::InstructionError(0, InstructionError::ReentrancyNotAllowed)
+        );
+    }
+    assert_eq!(token_balance(&mut context, &pool.vault).await, 1_000_000);
+    assert_eq!(
+        token_balance(&mut context, &other_pool.vault).await,
+        1_000_000
+    );
+
+    // the program also rejects them itself while a flash loan is calling its receiver
+    let mut pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    pool_authority.flash_loan_in_progress = 1;
+    set_zero_copy_account(&mut context, &pool.pool_authority, &pool_authority);
+    for instruction in [
+        pool.borrow_ix(&borrower.key(), &borrower.token_account, 100_000),
+        pool.borrow_with_receipt_ix(&borrower.key(), &borrower.token_account, 100_000),
+        pool.flash_loan_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            100_000,
+            100_000 + fee(100_000, LOAN_FEE),
+        ),
+        pool.deposit_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            &borrower.share_account,
+            100_000,
+        ),
+    ] {
+        assert_error(
+            process(&mut context, &[instruction], &[&borrower.keypair]).await,
+            0,
+            FlashLoanError::FlashLoanInProgress,
+        );
+    }
+    pool_authority.flash_loan_in_progress = 0;
+    set_zero_copy_account(&mut context, &pool.pool_authority, &pool_authority);
+
+    // the flag is cleared once the loan is repaid
+    process(
+        &mut context,
+        &[
+            pool.flash_loan_ix(
+                &borrower.key(),
+                &borrower.token_account,
+                100_000,
+                100_000 + fee(100_000, LOAN_FEE),
+            ),
+            pool.borrow_ix(&borrower.key(), &borrower.token_account, 200_000),
+            pool.repay_ix(
+                &borrower.key(),
+                &borrower.token_account,
+                200_000 + fee(200_000, LOAN_FEE + REFERRAL_FEE),
+                None,
+            ),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.flash_loan_in_progress, 0);
+}
+
+#[tokio::test]
+async fn test_flash_loan_receiver_checks() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+    let other_mint = create_mint(&mut context, &borrower.key(), 6).await;
+    let other_token_account =
+        create_token_account(&mut context, &other_mint, &borrower.key()).await;
+
+    // a receiver declared with the macro accepts loans from `flash_loan`
+    process(
+        &mut context,
+        &[pool.checked_flash_loan_ix(&borrower.key(), &borrower.token_account, 100_000)],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(100_000, LOAN_FEE)
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - fee(100_000, LOAN_FEE)
+    );
+
+    // including from a `flash_loan` called by another program
+    process(
+        &mut context,
+        &[proxy_ix(pool.checked_flash_loan_ix(
+            &borrower.key(),
+            &borrower.token_account,
+            200_000,
+        ))],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + fee(100_000, LOAN_FEE) + fee(200_000, LOAN_FEE)
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - fee(100_000, LOAN_FEE) - fee(200_000, LOAN_FEE)
+    );
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!({ pool_stats.total_loans }, 2);
+
+    // it rejects calls not signed by the flash loan signer, and accounts not matching the payload
+    let on_flash_loan = |amount, token_account: &Pubkey, pool_vault: &Pubkey| {
+        let mut instruction = flash_loan_receiver::on_flash_loan_ix(
+            &checked_receiver_program_id(),
+            &borrower.key(),
+            token_account,
+            pool_vault,
+            &FlashLoanPayload {
+                initiator: borrower.key(),
+                mint: pool.mint,
+                amount,
+                fee: 0,
+                data: vec![],
+            },
+            vec![],
+        )
+        .unwrap();
+        // only Flash Loan Mastery can sign for it
+        instruction.accounts[4].is_signer = false;
+        instruction
+    };
+    let mut other_signer = on_flash_loan(1_500, &borrower.token_account, &pool.vault);
+    other_signer.accounts[4] = AccountMeta::new_readonly(borrower.key(), true);
+    let receiver_error = |error: ReceiverError| {
+        TransactionError::InstructionError(0, InstructionError::Custom(error.into()))
+    };
+    for (instruction, error) in [
+        (
+            on_flash_loan(1_000, &borrower.token_account, &pool.vault),
+            ReceiverError::CallerMismatch,
+        ),
+        (other_signer, ReceiverError::CallerMismatch),
+        (
+            on_flash_loan(2_000, &borrower.token_account, &lender.token_account),
+            ReceiverError::PoolVaultMismatch,
+        ),
+        (
+            on_flash_loan(3_000, &other_token_account, &pool.vault),
+            ReceiverError::MintMismatch,
+        ),
+    ] {
+        assert_eq!(
+            process(&mut context, &[instruction], &[&borrower.keypair])
+                .await
+                .unwrap_err(),
+            receiver_error(error)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_borrow_through_cpi() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let lender = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &lender, 1_000_000).await;
+    let borrower = User::create(&mut context, &pool, 10_000).await;
+
+    // borrowing from another program
+    assert_error(
+        process(
+            &mut context,
+            &[
+                proxy_ix(pool.borrow_ix(&borrower.key(), &borrower.token_account, 100_000)),
+                pool.repay_ix(&borrower.key(), &borrower.token_account, 200_000, None),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+
+    let amount = 100_000;
+    let repayment = amount + fee(amount, LOAN_FEE + REFERRAL_FEE);
+    let borrow = |amount| {
+        pool.with_cpi_allowlist(pool.borrow_ix(&borrower.key(), &borrower.token_account, amount))
+    };
+    let repay = |amount| pool.repay_ix(&borrower.key(), &borrower.token_account, amount, None);
+
+    // the program is not on the allowlist
+    pool.create_cpi_allowlist(&mut context, &[]).await;
+    assert_error(
+        process(
+            &mut context,
+            &[proxy_ix(borrow(amount + 5)), repay(repayment)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+
+    // the allowlist is missing
+    let admin = context.payer.pubkey();
+    process(
+        &mut context,
+        &[pool.add_cpi_program_ix(&admin, &proxy_program_id())],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_error(
+        process(
+            &mut context,
+            &[
+                proxy_ix(pool.borrow_ix(&borrower.key(), &borrower.token_account, amount + 1)),
+                repay(repayment),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+
+    // the allowlist of another pool
+    let other_pool = Pool::create(&mut context).await;
+    other_pool
+        .create_cpi_allowlist(&mut context, &[proxy_program_id()])
+        .await;
+    assert_error(
+        process(
+            &mut context,
+            &[
+                proxy_ix(other_pool.with_cpi_allowlist(pool.borrow_ix(
+                    &borrower.key(),
+                    &borrower.token_account,
+                    amount + 2,
+                ))),
+                repay(repayment),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+
+    // the allowlisted program is called through another program
+    assert_error(
+        process(
+            &mut context,
+            &[proxy_ix(proxy_ix(borrow(amount + 3))), repay(repayment)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+
+    // the loan must still be repaid at the top of the transaction
+    assert_error(
+        process(
+            &mut context,
+            &[
+                proxy_ix(borrow(amount)),
+                proxy_ix(pool.with_cpi_allowlist(repay(repayment))),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::NoRepaymentInstructionFound,
+    );
+
+    // borrowing from an allowlisted program
+    process(
+        &mut context,
+        &[proxy_ix(borrow(amount)), repay(repayment)],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    let loan_fee = fee(amount, LOAN_FEE);
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + loan_fee
+    );
+    assert_eq!(
+        token_balance(&mut context, &borrower.token_account).await,
+        10_000 - loan_fee
+    );
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.loan_outstanding, 0);
+
+    // the repay of a loan borrowed through CPI can't be checked to cover a second CPI borrow
+    // before it, whether in the same instruction or after a borrow at the top of the transaction
+    process(
+        &mut context,
+        &[pool.add_cpi_program_ix(&admin, &batch_proxy_program_id())],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_error(
+        process(
+            &mut context,
+            &[
+                batch_proxy_ix(vec![borrow(amount), borrow(amount)]),
+                repay(repayment * 2),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                batch_proxy_ix(vec![
+                    borrow(amount),
+                    pool.with_cpi_allowlist(repay(1)),
+                    borrow(amount),
+                ]),
+                repay(repayment * 2),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                pool.borrow_ix(&borrower.key(), &borrower.token_account, amount),
+                proxy_ix(borrow(amount)),
+                repay(repayment * 2),
+            ],
+            &[&borrower.keypair],
+        )
+        .await,
+        1,
+        FlashLoanError::CannotBorrowBeforeRepay,
+    );
+
+    // repaying from an allowlisted program
+    process(
+        &mut context,
+        &[
+            pool.borrow_ix(&borrower.key(), &borrower.token_account, amount),
+            repay(repayment),
+            proxy_ix(pool.with_cpi_allowlist(repay(0))),
+        ],
+        &[&borrower.keypair],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &pool.vault).await,
+        1_000_000 + loan_fee * 2
+    );
+
+    // the program is removed from the allowlist
+    process(
+        &mut context,
+        &[pool.remove_cpi_program_ix(&admin, &proxy_program_id())],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_error(
+        process(
+            &mut context,
+            &[proxy_ix(borrow(amount + 4)), repay(repayment)],
+            &[&borrower.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::ProgramMismatch,
+    );
+}
+
+#[tokio::test]
+async fn test_cpi_allowlist() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    pool.create_cpi_allowlist(&mut context, &[]).await;
+    let admin = context.payer.pubkey();
+    let not_admin = Keypair::new();
+    let programs: Vec<Pubkey> = (0..=MAX_CPI_PROGRAMS)
+        .map(|i| Pubkey::new_from_array([100 + u8::try_from(i).unwrap(); 32]))
+        .collect();
+
+    assert_error(
+        process(
+            &mut context,
+            &[pool.add_cpi_program_ix(&not_admin.pubkey(), &programs[0])],
+            &[&not_admin],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[pool.add_cpi_program_ix(&admin, &Pubkey::default())],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::AddressMismatch,
+    );
+
+    let add_all: Vec<_> = programs[..MAX_CPI_PROGRAMS]
+        .iter()
+        .map(|program| pool.add_cpi_program_ix(&admin, program))
+        .collect();
+    process(&mut context, &add_all, &[]).await.unwrap();
+    let cpi_allowlist: CpiAllowlist = load_account(&mut context, &pool.cpi_allowlist).await;
+    assert_eq!(cpi_allowlist.programs, programs[..MAX_CPI_PROGRAMS]);
+
+    // adding a program twice does nothing
+    process(
+        &mut context,
+        &[pool.add_cpi_program_ix(&admin, &programs[1])],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_error(
+        process(
+            &mut context,
+            &[pool.add_cpi_program_ix(&admin, &programs[MAX_CPI_PROGRAMS])],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::CpiAllowlistFull,
+    );
+
+    assert_error(
+        process(
+            &mut context,
+            &[pool.remove_cpi_program_ix(&not_admin.pubkey(), &programs[0])],
+            &[&not_admin],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+    // the freed entry is reused
+    process(
+        &mut context,
+        &[
+            pool.remove_cpi_program_ix(&admin, &programs[0]),
+            pool.add_cpi_program_ix(&admin, &programs[MAX_CPI_PROGRAMS]),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    let cpi_allowlist: CpiAllowlist = load_account(&mut context, &pool.cpi_allowlist).await;
+    assert_eq!(cpi_allowlist.programs[0], programs[MAX_CPI_PROGRAMS]);
+    assert!(!cpi_allowlist.contains(&programs[0]));
+    assert_eq!(cpi_allowlist.programs[1..], programs[1..MAX_CPI_PROGRAMS]);
+}
+
+#[tokio::test]
+async fn test_init_cpi_allowlist() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let payer = context.payer.pubkey();
+
+    process(&mut context, &[pool.init_cpi_allowlist_ix(&payer)], &[])
+        .await
+        .unwrap();
+    let cpi_allowlist: CpiAllowlist = load_account(&mut context, &pool.cpi_allowlist).await;
+    assert_eq!({ cpi_allowlist.pool_authority }, pool.pool_authority);
+    assert_eq!(
+        cpi_allowlist.programs,
+        [Pubkey::default(); MAX_CPI_PROGRAMS]
+    );
+}
+
+#[tokio::test]
+async fn test_set_pool_config() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let admin = context.payer.pubkey();
+    let not_admin = Keypair::new();
+
+    process(
+        &mut context,
+        &[pool.set_pool_config_ix(&admin, 3000, 100)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let pool_config: PoolConfig = load_account(&mut context, &pool.pool_config).await;
+    assert_eq!({ pool_config.loan_fee_millionths }, 3000);
+    assert_eq!({ pool_config.referral_fee_millionths }, 100);
+
+    assert_error(
+        process(
+            &mut context,
+            &[pool.set_pool_config_ix(&admin, 99, 100)],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::InvalidFee,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[pool.set_pool_config_ix(&admin, 3000, 5001)],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::InvalidFee,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[pool.set_pool_config_ix(&not_admin.pubkey(), 3000, 100)],
+            &[&not_admin],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_admin() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let admin = context.payer.pubkey();
+    let new_admin = Keypair::new();
+    let not_admin = Keypair::new();
+
+    // only the admin can propose a new admin
+    assert_error(
+        process(
+            &mut context,
+            &[pool.propose_admin_ix(&not_admin.pubkey(), &not_admin.pubkey())],
+            &[&not_admin],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+    process(
+        &mut context,
+        &[pool.propose_admin_ix(&admin, &new_admin.pubkey())],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // only the proposed admin can accept
+    assert_error(
+        process(
+            &mut context,
+            &[pool.accept_admin_ix(&not_admin.pubkey())],
+            &[&not_admin],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+    process(
+        &mut context,
+        &[pool.accept_admin_ix(&new_admin.pubkey())],
+        &[&new_admin],
+    )
+    .await
+    .unwrap();
+
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.admin, new_admin.pubkey());
+    assert_eq!(pool_authority.pending_admin, Pubkey::default());
+
+    // the previous admin lost its rights
+    assert_error(
+        process(&mut context, &[pool.set_guardian_ix(&admin, &admin)], &[]).await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+}
+
+#[tokio::test]
+async fn test_migrate_pool_authority() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let admin = context.payer.pubkey();
+    let user = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &user, 500_000).await;
+    pool.set_legacy_pool_authority(&mut context);
+    let withdraw = |amount| {
+        pool.withdraw_ix(
+            &user.key(),
+            &user.token_account,
+            &user.share_account,
+            amount,
+        )
+    };
+
+    // legacy pools are rejected until they are migrated
+    assert_error(
+        process(&mut context, &[withdraw(1_000)], &[&user.keypair]).await,
+        0,
+        FlashLoanError::PoolNotMigrated,
+    );
+    assert_error(
+        process(&mut context, &[pool.set_guardian_ix(&admin, &admin)], &[]).await,
+        0,
+        FlashLoanError::PoolNotMigrated,
+    );
+
+    // anyone can migrate a pool, and migrating it again does nothing
+    let funder = Keypair::new();
+    airdrop(&mut context, &funder.pubkey(), LAMPORTS_PER_SOL).await;
+    for funder in [&user.keypair, &funder] {
+        process(
+            &mut context,
+            &[pool.migrate_pool_authority_ix(&funder.pubkey())],
+            &[funder],
+        )
+        .await
+        .unwrap();
+        let account = context
+            .banks_client
+            .get_account(pool.pool_authority)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.data.len(), 8 + std::mem::size_of::<PoolAuthority>());
+        let rent = context.banks_client.get_rent().await.unwrap();
+        assert!(rent.is_exempt(account.lamports, account.data.len()));
+        let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+        assert_eq!(pool_authority.mint, pool.mint);
+        assert_eq!(pool_authority.pool_share_mint, pool.pool_share_mint);
+        assert_eq!(pool_authority.bump, pool.pool_authority_bump());
+        assert_eq!(pool_authority.admin, Pubkey::default());
+        assert_eq!(pool_authority.guardian, Pubkey::default());
+        assert_eq!(pool_authority.paused, 0);
+        assert_eq!(pool_authority.migrated, 1);
+    }
+
+    // the pool works again, without an admin until the upgrade authority sets one
+    process(&mut context, &[withdraw(2_000)], &[&user.keypair])
+        .await
+        .unwrap();
+    assert_error(
+        process(
+            &mut context,
+            &[pool.set_guardian_ix(&admin, &user.key())],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+
+    // the admin of a migrated pool can only be set once
+    set_program_data(&mut context, &admin);
+    let mut pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    pool_authority.admin = admin;
+    set_zero_copy_account(&mut context, &pool.pool_authority, &pool_authority);
+    assert_error(
+        process(
+            &mut context,
+            &[pool.init_pool_admin_ix(&admin, &user.key())],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::AlreadyMigrated,
+    );
+
+    // pools created with an admin can't be claimed, even if their admin was cleared
+    let other_pool = Pool::create(&mut context).await;
+    let mut pool_authority: PoolAuthority =
+        load_account(&mut context, &other_pool.pool_authority).await;
+    pool_authority.admin = Pubkey::default();
+    set_zero_copy_account(&mut context, &other_pool.pool_authority, &pool_authority);
+    assert_error(
+        process(
+            &mut context,
+            &[other_pool.init_pool_admin_ix(&admin, &admin)],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::NotLegacyPool,
+    );
+}
+
+#[tokio::test]
+async fn test_pause() {
+    let mut context = start().await;
+    let pool = Pool::create(&mut context).await;
+    let admin = context.payer.pubkey();
+    let guardian = Keypair::new();
+    let user = User::create(&mut context, &pool, 1_000_000).await;
+    deposit(&mut context, &pool, &user, 500_000).await;
+
+    // the guardian can't be unset
+    assert_error(
+        process(
+            &mut context,
+            &[pool.set_guardian_ix(&admin, &Pubkey::default())],
+            &[],
+        )
+        .await,
+        0,
+        FlashLoanError::AddressMismatch,
+    );
+
+    // only the guardian or the admin can pause
+    let not_guardian = Keypair::new();
+    assert_error(
+        process(
+            &mut context,
+            &[pool.pause_ix(&not_guardian.pubkey())],
+            &[&not_guardian],
+        )
+        .await,
+        0,
+        FlashLoanError::GuardianOnly,
+    );
+    process(&mut context, &[pool.pause_ix(&admin)], &[])
+        .await
+        .unwrap();
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.paused, 1);
+    process(
+        &mut context,
+        &[
+            pool.unpause_ix(&admin),
+            pool.set_guardian_ix(&admin, &guardian.pubkey()),
+        ],
+        &[],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[pool.pause_ix(&guardian.pubkey())],
+        &[&guardian],
+    )
+    .await
+    .unwrap();
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.guardian, guardian.pubkey());
+    assert_eq!(pool_authority.paused, 1);
+
+    // deposits and loans are rejected
+    let ix = pool.deposit_ix(&user.key(), &user.token_account, &user.share_account, 1_000);
+    assert_error(
+        process(&mut context, &[ix], &[&user.keypair]).await,
+        0,
+        FlashLoanError::PoolPaused,
+    );
+    assert_error(
+        process(
+            &mut context,
+            &[
+                pool.borrow_ix(&user.key(), &user.token_account, 1_000),
+                pool.repay_ix(&user.key(), &user.token_account, 2_000, None),
+            ],
+            &[&user.keypair],
+        )
+        .await,
+        0,
+        FlashLoanError::PoolPaused,
+    );
+
+    // withdrawals still work
+    let ix = pool.withdraw_ix(&user.key(), &user.token_account, &user.share_account, 1_000);
+    process(&mut context, &[ix], &[&user.keypair])
+        .await
+        .unwrap();
+
+    // only the admin can unpause
+    assert_error(
+        process(
+            &mut context,
+            &[pool.unpause_ix(&guardian.pubkey())],
+            &[&guardian],
+        )
+        .await,
+        0,
+        FlashLoanError::AdminOnly,
+    );
+    process(&mut context, &[pool.unpause_ix(&admin)], &[])
+        .await
+        .unwrap();
+    deposit(&mut context, &pool, &user, 2_000).await;
+}
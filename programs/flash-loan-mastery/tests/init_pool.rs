@@ -0,0 +1,114 @@
+mod common;
+
+use anchor_lang::prelude::*;
+use common::*;
+use flash_loan_mastery::{
+    FlashLoanError, PoolAuthority, PoolConfig, PoolStats, LOAN_FEE, REFERRAL_FEE,
+};
+use solana_program_test::{tokio, ProgramTestContext};
+use solana_sdk::program_option::COption;
+use solana_sdk::program_pack::Pack;
+use solana_sdk::signature::Signer;
+
+async fn init_pool(
+    context: &mut ProgramTestContext,
+    decimals: u8,
+    supply: u64,
+) -> (
+    Pool,
+    std::result::Result<(), solana_sdk::transaction::TransactionError>,
+) {
+    let payer = context.payer.pubkey();
+    let mint = create_mint(context, &payer, 9).await;
+    let pool_share_mint = create_mint(context, &payer, decimals).await;
+    if supply > 0 {
+        let token_account = create_token_account(context, &pool_share_mint, &payer).await;
+        mint_to(context, &pool_share_mint, &token_account, supply).await;
+    }
+    let pool = Pool::new(mint, pool_share_mint);
+    let result = process(
+        context,
+        &[
+            pool.init_pool_ix(&payer),
+            pool.init_pool_config_ix(&payer),
+            pool.init_pool_stats_ix(&payer),
+        ],
+        &[],
+    )
+    .await;
+    (pool, result)
+}
+
+#[tokio::test]
+async fn test_init_pool() {
+    let mut context = start().await;
+    let (pool, result) = init_pool(&mut context, 9, 0).await;
+    result.unwrap();
+
+    let pool_authority: PoolAuthority = load_account(&mut context, &pool.pool_authority).await;
+    assert_eq!(pool_authority.mint, pool.mint);
+    assert_eq!(pool_authority.pool_share_mint, pool.pool_share_mint);
+    assert_eq!(pool_authority.bump, pool.pool_authority_bump());
+    assert_eq!(pool_authority.admin, context.payer.pubkey());
+    assert_eq!(pool_authority.pending_admin, Pubkey::default());
+    assert_eq!(pool_authority.guardian, Pubkey::default());
+    assert_eq!(pool_authority.paused, 0);
+
+    let share_mint_account = context
+        .banks_client
+        .get_account(pool.pool_share_mint)
+        .await
+        .unwrap()
+        .unwrap();
+    let share_mint = spl_token::state::Mint::unpack(&share_mint_account.data).unwrap();
+    assert_eq!(
+        share_mint.mint_authority,
+        COption::Some(pool.pool_authority)
+    );
+    assert_eq!(share_mint.freeze_authority, COption::None);
+
+    let pool_config: PoolConfig = load_account(&mut context, &pool.pool_config).await;
+    assert_eq!(pool_config.pool_authority, pool.pool_authority);
+    assert_eq!({ pool_config.loan_fee_millionths }, LOAN_FEE);
+    assert_eq!({ pool_config.referral_fee_millionths }, REFERRAL_FEE);
+
+    let pool_stats: PoolStats = load_account(&mut context, &pool.pool_stats).await;
+    assert_eq!(pool_stats.pool_authority, pool.pool_authority);
+    assert_eq!({ pool_stats.total_loans }, 0);
+}
+
+#[tokio::test]
+async fn test_init_pool_invalid_share_mint() {
+    let mut context = start().await;
+
+    // the share mint must have the same decimals as the mint
+    let (_, result) = init_pool(&mut context, 6, 0).await;
+    assert_error(result, 0, FlashLoanError::InvalidMintDecimals);
+
+    // the share mint must not have a supply
+    let (_, result) = init_pool(&mut context, 9, 1).await;
+    assert_error(result, 0, FlashLoanError::InvalidMintSupply);
+}
+
+#[tokio::test]
+async fn test_init_pool_default_admin() {
+    let mut context = start().await;
+    let payer = context.payer.pubkey();
+    let mint = create_mint(&mut context, &payer, 9).await;
+    let pool_share_mint = create_mint(&mut context, &payer, 9).await;
+
+    // a pool must be created with an admin
+    let result = process(
+        &mut context,
+        &[with_events(flash_loan_mastery_client::init_pool_ix(
+            &payer,
+            &mint,
+            &pool_share_mint,
+            &payer,
+            &Pubkey::default(),
+        ))],
+        &[],
+    )
+    .await;
+    assert_error(result, 0, FlashLoanError::AddressMismatch);
+}
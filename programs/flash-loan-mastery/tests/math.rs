@@ -0,0 +1,193 @@
+use flash_loan_mastery::math::{
+    deposit_shares, loan_fee, mint_shares_cost, redeem_tokens, repayment_amount, split_repayment,
+    withdraw_shares,
+};
+use flash_loan_mastery::{
+    FlashLoanError, MAX_LOAN_FEE_MILLIONTHS, MAX_REFERRAL_FEE_MILLIONTHS, MIN_LOAN_FEE_MILLIONTHS,
+};
+use proptest::prelude::*;
+
+/// Amounts large enough to cover any realistic pool without overflowing the share math
+const MAX_AMOUNT: u64 = 1 << 48;
+
+/// Unwrap a share amount, or `None` if it doesn't fit in a `u64` and the program would reject it
+fn share_amount(result: anchor_lang::Result<u64>) -> Option<u64> {
+    match result {
+        Ok(amount) => Some(amount),
+        Err(error) => {
+            assert_eq!(error, FlashLoanError::MathOverflow.into());
+            None
+        }
+    }
+}
+
+/// A pool tracked through the same math as the program
+#[derive(Clone, Copy, Debug, Default)]
+struct Pool {
+    amount: u64,
+    share_supply: u64,
+}
+
+impl Pool {
+    /// Deposit `amount`, returning `None` if the program would reject the deposit
+    fn deposit(&mut self, amount: u64) -> Option<u64> {
+        let shares = share_amount(deposit_shares(amount, self.amount, self.share_supply))?;
+        if shares == 0 {
+            return None;
+        }
+        self.amount += amount;
+        self.share_supply += shares;
+        Some(shares)
+    }
+
+    fn withdraw(&mut self, shares: u64) -> u64 {
+        let amount = redeem_tokens(shares, self.amount, self.share_supply).unwrap();
+        assert!(amount <= self.amount, "withdrew more than the vault holds");
+        self.amount -= amount;
+        self.share_supply -= shares;
+        amount
+    }
+
+    /// Borrow `amount` and repay it with `extra` on top of the minimum repayment
+    fn flash_loan(&mut self, amount: u64, loan_fee: u64, referral_fee: u64, extra: u64) {
+        let repayment = repayment_amount(amount, loan_fee, referral_fee).unwrap() + extra;
+        let split = split_repayment(repayment, loan_fee, referral_fee).unwrap();
+        self.amount = self.amount - amount + split.pool_repayment;
+    }
+
+    /// The value of `shares` pool shares, or 0 if there are no shares to redeem
+    fn value(&self, shares: u64) -> u64 {
+        if self.share_supply == 0 {
+            0
+        } else {
+            redeem_tokens(shares, self.amount, self.share_supply).unwrap()
+        }
+    }
+}
+
+prop_compose! {
+    fn pool()(amount in 0..MAX_AMOUNT, share_supply in 0..MAX_AMOUNT) -> Pool {
+        Pool { amount, share_supply }
+    }
+}
+
+prop_compose! {
+    fn fees()(
+        loan_fee in MIN_LOAN_FEE_MILLIONTHS..=MAX_LOAN_FEE_MILLIONTHS,
+        referral_fee in 0..=MAX_REFERRAL_FEE_MILLIONTHS,
+    ) -> (u64, u64) {
+        (loan_fee, referral_fee)
+    }
+}
+
+proptest! {
+    #[test]
+    fn deposit_then_withdraw_never_profits(mut pool in pool(), amount in 1..MAX_AMOUNT) {
+        if let Some(shares) = pool.deposit(amount) {
+            prop_assert!(pool.withdraw(shares) <= amount);
+        }
+    }
+
+    #[test]
+    fn exact_amounts_round_in_favour_of_the_pool(
+        pool in pool(),
+        amount in 1..MAX_AMOUNT,
+    ) {
+        if let Some(cost) = share_amount(mint_shares_cost(amount, pool.amount, pool.share_supply)) {
+            prop_assert!(deposit_shares(cost, pool.amount, pool.share_supply).unwrap() >= amount);
+        }
+
+        if pool.share_supply > 0 {
+            if let Some(shares) =
+                share_amount(withdraw_shares(amount, pool.amount, pool.share_supply))
+            {
+                prop_assert!(
+                    redeem_tokens(shares, pool.amount, pool.share_supply).unwrap() >= amount
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn flash_loans_never_decrease_share_price(
+        (mut pool, amount) in pool().prop_flat_map(|pool| (Just(pool), 1..=pool.amount.max(1))),
+        (loan_fee, referral_fee) in fees(),
+        extra in 0..MAX_AMOUNT,
+    ) {
+        prop_assume!(amount <= pool.amount);
+        let value_before = pool.value(pool.share_supply);
+        pool.flash_loan(amount, loan_fee, referral_fee, extra);
+        prop_assert!(pool.value(pool.share_supply) >= value_before);
+    }
+
+    #[test]
+    fn withdrawals_never_exceed_vault(
+        deposits in prop::collection::vec(1..MAX_AMOUNT, 1..10),
+        loans in prop::collection::vec((1..MAX_AMOUNT, 0..1_000u64), 0..5),
+        (loan_fee, referral_fee) in fees(),
+        donation in 0..MAX_AMOUNT,
+        order in Just((0..10usize).collect::<Vec<_>>()).prop_shuffle(),
+    ) {
+        let mut pool = Pool::default();
+        let mut positions = vec![];
+        for amount in deposits {
+            positions.extend(pool.deposit(amount));
+        }
+        for (amount, extra) in loans {
+            if amount <= pool.amount {
+                pool.flash_loan(amount, loan_fee, referral_fee, extra);
+            }
+        }
+        pool.amount += donation;
+
+        let vault = pool.amount;
+        let mut withdrawn = 0;
+        for index in order.into_iter().filter(|index| *index < positions.len()) {
+            withdrawn += pool.withdraw(positions[index]);
+        }
+        prop_assert!(withdrawn <= vault);
+        prop_assert_eq!(pool.share_supply, 0);
+    }
+
+    #[test]
+    fn loan_fee_never_overflows(
+        amount in 1..=u64::MAX,
+        (loan_fee_millionths, referral_fee_millionths) in fees(),
+    ) {
+        let fee = loan_fee(amount, loan_fee_millionths, referral_fee_millionths).unwrap();
+        prop_assert!(fee <= amount);
+        // the repayment only fails if it doesn't fit in a token amount
+        prop_assert_eq!(
+            repayment_amount(amount, loan_fee_millionths, referral_fee_millionths).is_ok(),
+            amount.checked_add(fee).is_some()
+        );
+    }
+
+    #[test]
+    fn split_repayment_never_overflows(amount in any::<u64>(), (loan_fee, referral_fee) in fees()) {
+        let split = split_repayment(amount, loan_fee, referral_fee).unwrap();
+        prop_assert!(split.principal <= amount);
+        prop_assert!(split.referral_fee <= split.principal);
+        prop_assert_eq!(split.pool_repayment + split.referral_fee, amount);
+    }
+
+    #[test]
+    fn split_repayment_covers_principal_and_loan_fee(
+        principal in 1..=u64::MAX,
+        extra in any::<u64>(),
+        (loan_fee_millionths, referral_fee_millionths) in fees(),
+    ) {
+        // any repayment that `borrow` accepts
+        let minimum = repayment_amount(principal, loan_fee_millionths, referral_fee_millionths);
+        let Ok(minimum) = minimum else {
+            return Ok(());
+        };
+        let Some(amount) = minimum.checked_add(extra) else {
+            return Ok(());
+        };
+
+        let split = split_repayment(amount, loan_fee_millionths, referral_fee_millionths).unwrap();
+        let pool_loan_fee = loan_fee(principal, loan_fee_millionths, 0).unwrap();
+        prop_assert!(split.pool_repayment >= principal + pool_loan_fee);
+    }
+}
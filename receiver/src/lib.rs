@@ -0,0 +1,448 @@
+#![warn(missing_debug_implementations, clippy::pedantic)]
+#![allow(
+    clippy::module_name_repetitions,
+    clippy::missing_errors_doc,
+    clippy::result_large_err
+)]
+//! Interface of the `on_flash_loan` instruction the Flash Loan Mastery `flash_loan` instruction
+//! calls on receiver programs
+//!
+//! The receiver gets the initiator of the loan as a signer, the token account holding the
+//! borrowed funds, the pool vault, the [Token] program and the flash loan signer, a PDA of Flash
+//! Loan Mastery that signs the call, followed by the accounts passed to `flash_loan` after its
+//! own. It must pay back the loan and the fee to the pool vault before returning.
+//!
+//! `flash_loan` can be called by another program, but not with that program as the receiver, as
+//! Solana doesn't let a program be called back through another one.
+//!
+//! Anyone can call `on_flash_loan` with any accounts and payload, not only Flash Loan Mastery. A
+//! receiver must not trust the payload or the accounts: it should [`check_caller`] and check the
+//! token account and the pool vault against the payload before moving funds, or an attacker can
+//! have it "repay" its own tokens to an account of theirs.
+//!
+//! An Anchor program can declare the accounts with [`on_flash_loan_accounts`], which always puts
+//! the accounts above first and does these checks, and repay with [`OnFlashLoanAccounts::repay`]:
+//!
+//! ```ignore
+//! use anchor_lang::prelude::*;
+//! use flash_loan_receiver::prelude::*;
+//!
+//! on_flash_loan_accounts! {
+//!     pub struct OnFlashLoan<'info> {
+//!         /// CHECK: used by the strategy
+//!         pub market: UncheckedAccount<'info>,
+//!     }
+//! }
+//!
+//! #[program]
+//! pub mod my_receiver {
+//!     use super::*;
+//!
+//!     pub fn on_flash_loan(ctx: Context<OnFlashLoan>, payload: FlashLoanPayload) -> Result<()> {
+//!         // use the funds in `ctx.accounts.token_account`
+//!         ctx.accounts.repay(&payload)
+//!     }
+//! }
+//! ```
+//!
+//! [Token]: anchor_spl::token::Token
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program::invoke_signed;
+use anchor_spl::associated_token::get_associated_token_address;
+
+pub use anchor_lang;
+pub use anchor_spl;
+
+/// Everything needed to implement a receiver
+pub mod prelude {
+    pub use crate::{on_flash_loan_accounts, FlashLoanPayload, OnFlashLoanAccounts, ReceiverError};
+    pub use anchor_spl::token::{Token, TokenAccount};
+}
+
+/// The Flash Loan Mastery program, the only program whose loans a receiver should accept
+pub mod flash_loan_mastery {
+    use anchor_lang::declare_id;
+
+    declare_id!("1oanfPPN8r1i4UbugXHDxWMbWVJ5qLSN5qzNFZkz6Fg");
+
+    /// The seed of the pool authority of a mint
+    pub const POOL_SEED: &[u8] = b"flash_loan";
+    /// The seed of the PDA that signs the calls of `flash_loan` to receivers, it owns nothing
+    pub const FLASH_LOAN_SIGNER_SEED: &[u8] = b"flash_loan_signer";
+    /// The bump of the flash loan signer PDA
+    pub const FLASH_LOAN_SIGNER_BUMP: u8 = 254;
+}
+
+/// The instruction tag of `on_flash_loan`, this is the tag of an Anchor instruction of that name
+pub const ON_FLASH_LOAN_DISCRIMINATOR: [u8; 8] = [195, 212, 238, 236, 80, 204, 73, 167];
+
+/// Errors of the checks done by receivers
+///
+/// The codes start at 7000 to stay clear of the errors of the receiver program itself.
+#[error_code(offset = 7000)]
+pub enum ReceiverError {
+    /// 7000
+    #[msg("on_flash_loan was not called by the Flash Loan Mastery flash_loan instruction")]
+    CallerMismatch,
+    /// 7001
+    #[msg("The pool vault is not the vault of the pool of the payload mint")]
+    PoolVaultMismatch,
+    /// 7002
+    #[msg("The token account does not hold the payload mint")]
+    MintMismatch,
+    /// 7003
+    #[msg("The initiator is not the initiator of the payload")]
+    InitiatorMismatch,
+}
+
+/// The arguments of the `on_flash_loan` instruction
+///
+/// Anyone can call a receiver with any payload, it can only be trusted after [`check_caller`].
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FlashLoanPayload {
+    /// The entity that called `flash_loan`
+    pub initiator: Pubkey,
+    /// The mint of the borrowed token
+    pub mint: Pubkey,
+    /// The amount borrowed
+    pub amount: u64,
+    /// The fee due to the pool on top of `amount`
+    pub fee: u64,
+    /// The data passed to `flash_loan`
+    pub data: Vec<u8>,
+}
+
+impl FlashLoanPayload {
+    /// Get the amount to pay back to the pool vault, the loan and the fee
+    #[must_use]
+    pub fn repayment_amount(&self) -> Option<u64> {
+        self.amount.checked_add(self.fee)
+    }
+}
+
+/// Get the address of the Flash Loan Mastery pool vault of `mint`, the only account a loan of
+/// `mint` should be paid back to
+#[must_use]
+pub fn pool_vault_address(mint: &Pubkey) -> Pubkey {
+    let pool_authority = Pubkey::find_program_address(
+        &[flash_loan_mastery::POOL_SEED, mint.as_ref()],
+        &flash_loan_mastery::ID,
+    )
+    .0;
+    get_associated_token_address(&pool_authority, mint)
+}
+
+/// Get the address of the Flash Loan Mastery PDA that signs the calls of `flash_loan` to
+/// receivers
+pub fn flash_loan_signer_address() -> Result<Pubkey> {
+    Pubkey::create_program_address(
+        &[
+            flash_loan_mastery::FLASH_LOAN_SIGNER_SEED,
+            &[flash_loan_mastery::FLASH_LOAN_SIGNER_BUMP],
+        ],
+        &flash_loan_mastery::ID,
+    )
+    .map_err(|err| ProgramError::from(err).into())
+}
+
+/// Check that the current instruction was called by the Flash Loan Mastery `flash_loan`
+/// instruction
+///
+/// `flash_loan_signer` must be the flash loan signer PDA and sign the instruction. Only Flash Loan
+/// Mastery can sign for it and it only does when `flash_loan` calls the receiver, whether that
+/// `flash_loan` is at the top of the transaction or called by another program.
+///
+/// The program calling `flash_loan` and the receiver must be different programs: Solana rejects a
+/// program calling itself back through another one, so a program can't receive the loans it
+/// takes through CPI and should hand them to a separate receiver program.
+pub fn check_caller(flash_loan_signer: &AccountInfo) -> Result<()> {
+    require!(flash_loan_signer.is_signer, ReceiverError::CallerMismatch);
+    require_keys_eq!(
+        *flash_loan_signer.key,
+        flash_loan_signer_address()?,
+        ReceiverError::CallerMismatch
+    );
+    Ok(())
+}
+
+/// Build the `on_flash_loan` instruction, `remaining_accounts` come after the accounts every
+/// receiver gets
+pub fn on_flash_loan_ix(
+    receiver_program: &Pubkey,
+    initiator: &Pubkey,
+    token_account: &Pubkey,
+    pool_vault: &Pubkey,
+    payload: &FlashLoanPayload,
+    remaining_accounts: impl IntoIterator<Item = AccountMeta>,
+) -> Result<Instruction> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*initiator, true),
+        AccountMeta::new(*token_account, false),
+        AccountMeta::new(*pool_vault, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new_readonly(flash_loan_signer_address()?, true),
+    ];
+    accounts.extend(remaining_accounts);
+    let mut data = ON_FLASH_LOAN_DISCRIMINATOR.to_vec();
+    data.extend(payload.try_to_vec()?);
+    Ok(Instruction {
+        program_id: *receiver_program,
+        accounts,
+        data,
+    })
+}
+
+/// Decode the payload of an `on_flash_loan` instruction, for receivers not using Anchor
+pub fn decode_on_flash_loan(data: &[u8]) -> Result<FlashLoanPayload> {
+    if data.len() < 8 || data[..8] != ON_FLASH_LOAN_DISCRIMINATOR {
+        return err!(ErrorCode::InstructionFallbackNotFound);
+    }
+    FlashLoanPayload::deserialize(&mut &data[8..])
+        .map_err(|_| error!(ErrorCode::InstructionDidNotDeserialize))
+}
+
+/// Build the transfer paying back the loan and the fee from `token_account` to `pool_vault`
+pub fn repayment_transfer_ix(
+    payload: &FlashLoanPayload,
+    token_account: &Pubkey,
+    pool_vault: &Pubkey,
+    authority: &Pubkey,
+) -> Result<Instruction> {
+    let amount = payload
+        .repayment_amount()
+        .ok_or(ProgramError::InvalidArgument)?;
+    Ok(spl_token::instruction::transfer(
+        &spl_token::ID,
+        token_account,
+        pool_vault,
+        authority,
+        &[],
+        amount,
+    )?)
+}
+
+/// The accounts every `on_flash_loan` instruction gets, implemented by
+/// [`on_flash_loan_accounts`]
+pub trait OnFlashLoanAccounts<'info> {
+    /// The entity that called `flash_loan`, it signs the instruction
+    fn initiator(&self) -> AccountInfo<'info>;
+    /// The token account holding the borrowed funds
+    fn token_account(&self) -> AccountInfo<'info>;
+    /// The pool vault the loan is paid back to
+    fn pool_vault(&self) -> AccountInfo<'info>;
+    /// The [Token](anchor_spl::token::Token) program
+    fn token_program(&self) -> AccountInfo<'info>;
+    /// The flash loan signer, used to [`check_caller`]
+    fn flash_loan_signer(&self) -> AccountInfo<'info>;
+
+    /// Pay back the loan and the fee, signed by the initiator
+    fn repay(&self, payload: &FlashLoanPayload) -> Result<()> {
+        self.repay_with_authority(payload, self.initiator(), &[])
+    }
+
+    /// Pay back the loan and the fee, signed by `authority`
+    ///
+    /// `signer_seeds` are needed when `authority` is a PDA of the receiver program.
+    fn repay_with_authority(
+        &self,
+        payload: &FlashLoanPayload,
+        authority: AccountInfo<'info>,
+        signer_seeds: &[&[&[u8]]],
+    ) -> Result<()> {
+        let ix = repayment_transfer_ix(
+            payload,
+            self.token_account().key,
+            self.pool_vault().key,
+            authority.key,
+        )?;
+        invoke_signed(
+            &ix,
+            &[
+                self.token_account(),
+                self.pool_vault(),
+                authority,
+                self.token_program(),
+            ],
+            signer_seeds,
+        )?;
+        Ok(())
+    }
+}
+
+/// Declare the Anchor accounts of an `on_flash_loan` instruction
+///
+/// The accounts every receiver gets are added before the declared ones, as `initiator`,
+/// `token_account`, `pool_vault`, `token_program` and `flash_loan_signer`, and
+/// [`OnFlashLoanAccounts`] is implemented for the struct. The names in [`prelude`] must be in
+/// scope.
+///
+/// The struct takes the [`FlashLoanPayload`] as its instruction argument, so it must be the first
+/// argument of the instruction and the struct can't declare another `#[instruction]`. The
+/// accounts are checked against it: the caller must pass [`check_caller`], `initiator` must be the
+/// payload initiator, `token_account` must hold the payload mint and `pool_vault` must be the
+/// [`pool_vault_address`] of that mint.
+#[macro_export]
+macro_rules! on_flash_loan_accounts {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident<$info:lifetime> {
+            $($fields:tt)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive($crate::anchor_lang::Accounts)]
+        #[instruction(payload: $crate::FlashLoanPayload)]
+        $vis struct $name<$info> {
+            /// The entity that called `flash_loan`
+            #[account(
+                constraint = initiator.key() == payload.initiator @$crate::ReceiverError::InitiatorMismatch,
+            )]
+            pub initiator: Signer<$info>,
+            /// The token account holding the borrowed funds
+            #[account(
+                mut,
+                constraint = token_account.mint == payload.mint @$crate::ReceiverError::MintMismatch,
+            )]
+            pub token_account: Account<$info, TokenAccount>,
+            /// The pool vault the loan is paid back to
+            #[account(
+                mut,
+                address = $crate::pool_vault_address(&payload.mint) @$crate::ReceiverError::PoolVaultMismatch,
+            )]
+            pub pool_vault: Account<$info, TokenAccount>,
+            /// The token program
+            pub token_program: Program<$info, Token>,
+            /// The Flash Loan Mastery PDA signing the call
+            /// CHECK: checked by `check_caller`
+            #[account(
+                constraint = $crate::check_caller(&flash_loan_signer).is_ok() @$crate::ReceiverError::CallerMismatch,
+            )]
+            pub flash_loan_signer: UncheckedAccount<$info>,
+            $($fields)*
+        }
+
+        impl<$info> $crate::OnFlashLoanAccounts<$info> for $name<$info> {
+            fn initiator(&self) -> $crate::anchor_lang::prelude::AccountInfo<$info> {
+                $crate::anchor_lang::ToAccountInfo::to_account_info(&self.initiator)
+            }
+
+            fn token_account(&self) -> $crate::anchor_lang::prelude::AccountInfo<$info> {
+                $crate::anchor_lang::ToAccountInfo::to_account_info(&self.token_account)
+            }
+
+            fn pool_vault(&self) -> $crate::anchor_lang::prelude::AccountInfo<$info> {
+                $crate::anchor_lang::ToAccountInfo::to_account_info(&self.pool_vault)
+            }
+
+            fn token_program(&self) -> $crate::anchor_lang::prelude::AccountInfo<$info> {
+                $crate::anchor_lang::ToAccountInfo::to_account_info(&self.token_program)
+            }
+
+            fn flash_loan_signer(&self) -> $crate::anchor_lang::prelude::AccountInfo<$info> {
+                $crate::anchor_lang::ToAccountInfo::to_account_info(&self.flash_loan_signer)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prelude::*;
+    use super::*;
+    use anchor_lang::solana_program::hash::hashv;
+
+    on_flash_loan_accounts! {
+        /// A receiver with one account of its own
+        #[allow(dead_code)]
+        pub struct TestReceiver<'info> {
+            /// CHECK: only used for its key
+            pub market: UncheckedAccount<'info>,
+        }
+    }
+
+    fn payload() -> FlashLoanPayload {
+        FlashLoanPayload {
+            initiator: Pubkey::new_from_array([1; 32]),
+            mint: Pubkey::new_from_array([2; 32]),
+            amount: 1_000_000,
+            fee: 900,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_discriminator() {
+        assert_eq!(
+            ON_FLASH_LOAN_DISCRIMINATOR,
+            hashv(&[b"global:on_flash_loan"]).to_bytes()[..8]
+        );
+    }
+
+    #[test]
+    fn test_account_layout() {
+        let initiator = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let pool_vault = Pubkey::new_unique();
+        let market = Pubkey::new_unique();
+        let ixn = on_flash_loan_ix(
+            &Pubkey::new_unique(),
+            &initiator,
+            &token_account,
+            &pool_vault,
+            &payload(),
+            vec![AccountMeta::new_readonly(market, false)],
+        )
+        .unwrap();
+        // the accounts the macro declares match the instruction `flash_loan` builds, the flash
+        // loan signer is checked by `check_caller` rather than declared as a signer
+        let mut accounts = ixn.accounts.clone();
+        accounts[4].is_signer = false;
+        assert_eq!(
+            accounts,
+            __client_accounts_test_receiver::TestReceiver {
+                initiator,
+                token_account,
+                pool_vault,
+                token_program: spl_token::ID,
+                flash_loan_signer: flash_loan_signer_address().unwrap(),
+                market,
+            }
+            .to_account_metas(None)
+        );
+        assert_eq!(decode_on_flash_loan(&ixn.data).unwrap(), payload());
+    }
+
+    #[test]
+    fn test_decode_other_instructions() {
+        assert_eq!(
+            decode_on_flash_loan(&[0; 8]),
+            Err(ErrorCode::InstructionFallbackNotFound.into())
+        );
+        assert_eq!(
+            decode_on_flash_loan(&ON_FLASH_LOAN_DISCRIMINATOR),
+            Err(ErrorCode::InstructionDidNotDeserialize.into())
+        );
+    }
+
+    #[test]
+    fn test_repayment_transfer() {
+        let token_account = Pubkey::new_unique();
+        let pool_vault = Pubkey::new_unique();
+        let ixn =
+            repayment_transfer_ix(&payload(), &token_account, &pool_vault, &token_account).unwrap();
+        assert_eq!(
+            ixn.data,
+            spl_token::instruction::TokenInstruction::Transfer { amount: 1_000_900 }.pack()
+        );
+
+        let payload = FlashLoanPayload {
+            amount: u64::MAX,
+            ..payload()
+        };
+        assert!(
+            repayment_transfer_ix(&payload, &token_account, &pool_vault, &token_account).is_err()
+        );
+    }
+}
@@ -0,0 +1,319 @@
+#![warn(missing_debug_implementations, clippy::pedantic)]
+#![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+//! Run native builds of Solana programs in tests the way the BPF loader runs them
+//!
+//! The native programs of `solana-program-test` 1.10 get copies of their accounts that can't
+//! change size, so they can't create accounts or call [`AccountInfo::realloc`], and they always
+//! see a stack height of 0. Programs wrapped with [`processor`] get their accounts in the layout
+//! the BPF loader serializes them in instead, and their syscalls are answered by the invoke
+//! context of the runtime, like those of a program running on chain.
+//!
+//! ```ignore
+//! let mut program_test = ProgramTest::default();
+//! program_test.add_program("my_program", my_program::ID, Some(processor!(my_program::entry)));
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Mutex};
+
+use solana_bpf_loader_program::serialization::{deserialize_parameters, serialize_parameters};
+use solana_program_runtime::stable_log;
+use solana_program_runtime::timings::ExecuteTimings;
+use solana_sdk::account::{ReadableAccount, WritableAccount};
+use solana_sdk::account_info::AccountInfo;
+use solana_sdk::entrypoint::{deserialize, ProcessInstruction, ProgramResult, SUCCESS};
+use solana_sdk::instruction::{get_stack_height, Instruction, InstructionError};
+use solana_sdk::program_error::{ProgramError, UNSUPPORTED_SYSVAR};
+use solana_sdk::program_stubs::{set_syscall_stubs, SyscallStubs};
+use solana_sdk::pubkey::Pubkey;
+
+pub use solana_program_runtime::invoke_context::InvokeContext;
+
+/// Wrap a `solana-program` style entrypoint so that it can be added to a `ProgramTest` or a
+/// `Bank` as a builtin program
+#[macro_export]
+macro_rules! processor {
+    ($process_instruction:expr) => {
+        |first_instruction_account: usize,
+         input: &[u8],
+         invoke_context: &mut $crate::InvokeContext| {
+            $crate::process_instruction(
+                $process_instruction,
+                first_instruction_account,
+                input,
+                invoke_context,
+            )
+        }
+    };
+}
+
+thread_local! {
+    /// The invoke context of the instruction being processed by this thread
+    static INVOKE_CONTEXT: Cell<Option<usize>> = const { Cell::new(None) };
+    /// The error of a failed call to another program, it aborts the calling program
+    static CALL_ERROR: RefCell<Option<InstructionError>> = const { RefCell::new(None) };
+}
+
+/// The data logged with `sol_log_data` by programs wrapped with [`processor`], oldest first
+static LOGGED_DATA: Mutex<Vec<Vec<Vec<u8>>>> = Mutex::new(Vec::new());
+/// The successful calls made by programs wrapped with [`processor`], oldest first
+static INVOKED_INSTRUCTIONS: Mutex<Vec<Instruction>> = Mutex::new(Vec::new());
+
+/// The fields of every `sol_log_data` call made so far, in any bank
+///
+/// Tests sharing a process all log here, so they should look for data they alone can log.
+pub fn logged_data() -> Vec<Vec<Vec<u8>>> {
+    LOGGED_DATA.lock().unwrap().clone()
+}
+
+/// Every successful call to another program made so far, in any bank
+///
+/// Tests sharing a process all call here, so they should look for calls they alone can make.
+pub fn invoked_instructions() -> Vec<Instruction> {
+    INVOKED_INSTRUCTIONS.lock().unwrap().clone()
+}
+
+fn set_invoke_context(invoke_context: &mut InvokeContext) {
+    let address = std::ptr::from_mut::<InvokeContext>(invoke_context) as usize;
+    INVOKE_CONTEXT.with(|current| current.set(Some(address)));
+}
+
+fn get_invoke_context<'a, 'b>() -> Option<&'a mut InvokeContext<'b>> {
+    let address = INVOKE_CONTEXT.with(Cell::get)?;
+    // the invoke context outlives every syscall made while its instruction is processed
+    Some(unsafe { &mut *(address as *mut InvokeContext) })
+}
+
+/// Process the current instruction of `invoke_context` with `process_instruction`
+///
+/// The accounts are serialized and deserialized the way the BPF loader does, with room for the
+/// program to grow them.
+pub fn process_instruction(
+    process_instruction: ProcessInstruction,
+    _first_instruction_account: usize,
+    _input: &[u8],
+    invoke_context: &mut InvokeContext,
+) -> Result<(), InstructionError> {
+    set_invoke_context(invoke_context);
+    // `solana-program-test` sets its own stubs when it creates its first bank, and those always
+    // answer 0
+    if get_stack_height() == 0 {
+        set_syscall_stubs(Box::new(NativeSyscallStubs));
+    }
+
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    let program_id = *instruction_context.get_program_key(transaction_context)?;
+    let (mut parameters, account_lengths) =
+        serialize_parameters(transaction_context, instruction_context)?;
+
+    // the account infos point into `parameters`, which lives until the accounts are written back
+    let (_, account_infos, instruction_data) =
+        unsafe { deserialize(parameters.as_slice_mut().as_mut_ptr()) };
+    let result = process_instruction(&program_id, &account_infos, instruction_data);
+    // on chain a failed call aborts the program, whatever it does with the error
+    if let Some(err) = CALL_ERROR.with(RefCell::take) {
+        return Err(err);
+    }
+    result.map_err(|err| InstructionError::from(u64::from(err)))?;
+    drop(account_infos);
+
+    // the instruction context is looked up again, calls to other programs may have moved it
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context.get_current_instruction_context()?;
+    deserialize_parameters(
+        transaction_context,
+        instruction_context,
+        parameters.as_slice(),
+        &account_lengths,
+        true,
+    )?;
+
+    Ok(())
+}
+
+/// Record the error of a failed call so that it aborts the calling program
+///
+/// The program still gets a `ProgramError` back, `InvalidArgument` if the runtime error has no
+/// equivalent.
+fn to_program_error(err: InstructionError) -> ProgramError {
+    CALL_ERROR.with(|call_error| *call_error.borrow_mut() = Some(err.clone()));
+    ProgramError::try_from(err).unwrap_or(ProgramError::InvalidArgument)
+}
+
+/// Call another program from the program being processed, like the `sol_invoke_signed` syscall
+fn invoke_signed(
+    invoke_context: &mut InvokeContext,
+    instruction: &Instruction,
+    account_infos: &[AccountInfo],
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let transaction_context = &invoke_context.transaction_context;
+    let instruction_context = transaction_context
+        .get_current_instruction_context()
+        .map_err(to_program_error)?;
+    let caller = *instruction_context
+        .get_program_key(transaction_context)
+        .map_err(to_program_error)?;
+    let signers = signers_seeds
+        .iter()
+        .map(|seeds| Pubkey::create_program_address(seeds, &caller))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (instruction_accounts, program_indices) = invoke_context
+        .prepare_instruction(instruction, &signers)
+        .map_err(to_program_error)?;
+
+    // the callee sees the changes made by the caller so far
+    let mut writable_accounts = Vec::with_capacity(instruction_accounts.len());
+    for instruction_account in &instruction_accounts {
+        let key = invoke_context
+            .transaction_context
+            .get_key_of_account_at_index(instruction_account.index_in_transaction)
+            .map_err(to_program_error)?;
+        let account_info_index = account_infos
+            .iter()
+            .position(|account_info| account_info.key == key)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let account_info = &account_infos[account_info_index];
+        let mut account = invoke_context
+            .transaction_context
+            .get_account_at_index(instruction_account.index_in_transaction)
+            .map_err(to_program_error)?
+            .borrow_mut();
+        account.copy_into_owner_from_slice(account_info.owner.as_ref());
+        account.set_data_from_slice(&account_info.try_borrow_data()?);
+        account.set_lamports(account_info.lamports());
+        account.set_executable(account_info.executable);
+        account.set_rent_epoch(account_info.rent_epoch);
+        if instruction_account.is_writable {
+            writable_accounts.push((instruction_account.index_in_transaction, account_info_index));
+        }
+    }
+
+    invoke_context
+        .process_instruction(
+            &instruction.data,
+            &instruction_accounts,
+            &program_indices,
+            &mut 0,
+            &mut ExecuteTimings::default(),
+        )
+        .map_err(to_program_error)?;
+    INVOKED_INSTRUCTIONS
+        .lock()
+        .unwrap()
+        .push(instruction.clone());
+
+    // and the caller sees the changes made by the callee
+    for (index_in_transaction, account_info_index) in writable_accounts {
+        let account = invoke_context
+            .transaction_context
+            .get_account_at_index(index_in_transaction)
+            .map_err(to_program_error)?
+            .borrow();
+        let account_info = &account_infos[account_info_index];
+        **account_info.try_borrow_mut_lamports()? = account.lamports();
+        if account_info.owner != account.owner() {
+            account_info.assign(account.owner());
+        }
+        account_info.realloc(account.data().len(), false)?;
+        account_info
+            .try_borrow_mut_data()?
+            .copy_from_slice(account.data());
+    }
+
+    Ok(())
+}
+
+fn get_sysvar<T: Clone>(sysvar: Result<Arc<T>, InstructionError>, var_addr: *mut u8) -> u64 {
+    match sysvar {
+        Ok(sysvar) => {
+            unsafe { *var_addr.cast::<T>() = T::clone(&sysvar) };
+            SUCCESS
+        }
+        Err(_) => UNSUPPORTED_SYSVAR,
+    }
+}
+
+/// Syscalls of programs wrapped with [`processor`]
+struct NativeSyscallStubs;
+
+impl SyscallStubs for NativeSyscallStubs {
+    fn sol_log(&self, message: &str) {
+        if let Some(invoke_context) = get_invoke_context() {
+            stable_log::program_log(&invoke_context.get_log_collector(), message);
+        }
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGGED_DATA
+            .lock()
+            .unwrap()
+            .push(fields.iter().map(|field| field.to_vec()).collect());
+        if let Some(invoke_context) = get_invoke_context() {
+            stable_log::program_data(&invoke_context.get_log_collector(), fields);
+        }
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let invoke_context = get_invoke_context().expect("no instruction is being processed");
+        invoke_signed(invoke_context, instruction, account_infos, signers_seeds)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        get_invoke_context().map_or(UNSUPPORTED_SYSVAR, |invoke_context| {
+            get_sysvar(invoke_context.get_sysvar_cache().get_clock(), var_addr)
+        })
+    }
+
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        get_invoke_context().map_or(UNSUPPORTED_SYSVAR, |invoke_context| {
+            get_sysvar(
+                invoke_context.get_sysvar_cache().get_epoch_schedule(),
+                var_addr,
+            )
+        })
+    }
+
+    #[allow(deprecated)]
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        get_invoke_context().map_or(UNSUPPORTED_SYSVAR, |invoke_context| {
+            get_sysvar(invoke_context.get_sysvar_cache().get_fees(), var_addr)
+        })
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        get_invoke_context().map_or(UNSUPPORTED_SYSVAR, |invoke_context| {
+            get_sysvar(invoke_context.get_sysvar_cache().get_rent(), var_addr)
+        })
+    }
+
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        let invoke_context = get_invoke_context()?;
+        let (program_id, data) = invoke_context.transaction_context.get_return_data();
+        Some((*program_id, data.to_vec()))
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        let invoke_context = get_invoke_context().expect("no instruction is being processed");
+        let transaction_context = &mut *invoke_context.transaction_context;
+        let caller = *transaction_context
+            .get_current_instruction_context()
+            .and_then(|instruction_context| {
+                instruction_context.get_program_key(transaction_context)
+            })
+            .unwrap();
+        transaction_context
+            .set_return_data(caller, data.to_vec())
+            .unwrap();
+    }
+
+    fn sol_get_stack_height(&self) -> u64 {
+        get_invoke_context().map_or(0, |invoke_context| invoke_context.get_stack_height() as u64)
+    }
+}
@@ -22,8 +22,9 @@ import { expect } from "chai";
 
 export const LOAN_FEE = 900;
 export const REFERRAL_FEE = 50;
-export const LOAN_FEE_DENOMINATOR = 10000;
-export const ONE_HUNDRED = 100;
+export const FEE_DENOMINATOR = 1_000_000;
+export const VIRTUAL_SHARES = 1_000;
+export const VIRTUAL_ASSETS = 1;
 
 describe("flash-loan-mastery", () => {
   // Configure the client to use the local cluster.
@@ -37,6 +38,8 @@ describe("flash-loan-mastery", () => {
   const depositor2 = new Keypair();
   const depositor3 = new Keypair();
   let poolAuthorityKey: PublicKey;
+  let poolConfigKey: PublicKey;
+  let poolStatsKey: PublicKey;
 
   it("init pool", async () => {
     // set up the mint and token accounts
@@ -67,7 +70,7 @@ describe("flash-loan-mastery", () => {
     poolAuthorityKey = poolAuthority[0];
 
     const initPoolIx = await program.methods
-      .initPool()
+      .initPool(wallet)
       .accountsStrict({
         funder: wallet,
         mint: tokenMint.publicKey,
@@ -89,6 +92,9 @@ describe("flash-loan-mastery", () => {
     expect(poolAuthorityAccount.poolShareMint.equals(poolMint.publicKey)).to.be
       .true;
     expect(poolAuthorityAccount.mint.equals(tokenMint.publicKey)).to.be.true;
+    expect(poolAuthorityAccount.admin.equals(wallet)).to.be.true;
+    expect(poolAuthorityAccount.pendingAdmin.equals(PublicKey.default)).to.be
+      .true;
 
     const poolShareMintAcc = await getMint(
       program.provider.connection,
@@ -96,6 +102,63 @@ describe("flash-loan-mastery", () => {
     );
     expect(poolShareMintAcc.mintAuthority.equals(poolAuthority[0])).to.be.true;
     expect(poolShareMintAcc.freezeAuthority).to.be.null;
+
+    // create pool config
+    const poolConfig = PublicKey.findProgramAddressSync(
+      [Buffer.from("pool_config"), poolAuthority[0].toBuffer()],
+      program.programId
+    );
+    poolConfigKey = poolConfig[0];
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await program.methods
+          .initPoolConfig()
+          .accountsStrict({
+            funder: wallet,
+            poolAuthority: poolAuthority[0],
+            poolConfig: poolConfig[0],
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction()
+      )
+    );
+
+    const poolConfigAccount = await program.account.poolConfig.fetch(
+      poolConfig[0]
+    );
+    expect(poolConfigAccount.bump).eq(poolConfig[1]);
+    expect(poolConfigAccount.poolAuthority.equals(poolAuthority[0])).to.be.true;
+    expect(poolConfigAccount.loanFeeMillionths.toNumber()).eq(LOAN_FEE);
+    expect(poolConfigAccount.referralFeeMillionths.toNumber()).eq(REFERRAL_FEE);
+
+    // create pool stats
+    const poolStats = PublicKey.findProgramAddressSync(
+      [Buffer.from("pool_stats"), poolAuthority[0].toBuffer()],
+      program.programId
+    );
+    poolStatsKey = poolStats[0];
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await program.methods
+          .initPoolStats()
+          .accountsStrict({
+            funder: wallet,
+            poolAuthority: poolAuthority[0],
+            poolStats: poolStats[0],
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction()
+      )
+    );
+
+    const poolStatsAccount = await program.account.poolStats.fetch(
+      poolStats[0]
+    );
+    expect(poolStatsAccount.bump).eq(poolStats[1]);
+    expect(poolStatsAccount.poolAuthority.equals(poolAuthority[0])).to.be.true;
+    expect(poolStatsAccount.totalLoans.toNumber()).eq(0);
   });
 
   it("deposit into pool", async () => {
@@ -217,8 +280,10 @@ describe("flash-loan-mastery", () => {
       "processed"
     );
     expect(tokenToAccAfter.amount).equals(BigInt(amount1.toString()));
-    expect(poolShareTokenToAccAfter.amount).equals(BigInt(amount1.toString()));
-    expect(poolShareMintAccAfter.supply).equals(BigInt(amount1.toString()));
+    // the first deposit gets `VIRTUAL_SHARES` shares per token
+    const shares1 = BigInt(amount1.toString()) * BigInt(VIRTUAL_SHARES);
+    expect(poolShareTokenToAccAfter.amount).equals(shares1);
+    expect(poolShareMintAccAfter.supply).equals(shares1);
     // 100% of pool shares
     expect(
       Number(poolShareTokenToAccAfter.amount) /
@@ -310,17 +375,18 @@ describe("flash-loan-mastery", () => {
     );
 
     const depositor3Shares = Math.floor(
-      (amount3.toNumber() * Number(poolShareMintAccAfter2.supply)) /
-        Number(tokenToAccAfter2b.amount)
+      (amount3.toNumber() *
+        (Number(poolShareMintAccAfter2.supply) + VIRTUAL_SHARES)) /
+        (Number(tokenToAccAfter2b.amount) + VIRTUAL_ASSETS)
     );
     expect(Number(poolShareMintAccAfter3.supply)).equals(
-      amount1.add(amount2).toNumber() + depositor3Shares
+      amount1.add(amount2).toNumber() * VIRTUAL_SHARES + depositor3Shares
     );
     // ~46% of pool shares
     expect(
       Number(poolShareTokenToAccAfter3.amount) /
         Number(poolShareMintAccAfter3.supply)
-    ).eq(100_000 / Number(poolShareMintAccAfter3.supply));
+    ).eq((100_000 * VIRTUAL_SHARES) / Number(poolShareMintAccAfter3.supply));
   });
 
   it("withdraw from pool", async () => {
@@ -359,7 +425,7 @@ describe("flash-loan-mastery", () => {
       "processed"
     );
 
-    const amount1 = new BN(50);
+    const amount1 = new BN(50_000);
     await program.provider.sendAndConfirm(
       new anchor.web3.Transaction().add(
         await program.methods
@@ -399,8 +465,9 @@ describe("flash-loan-mastery", () => {
     );
 
     const tokenValue = Math.floor(
-      (amount1.toNumber() * Number(tokenFromBefore.amount)) /
-        Number(poolShareMintAccBefore.supply)
+      (amount1.toNumber() *
+        (Number(tokenFromBefore.amount) + VIRTUAL_ASSETS)) /
+        (Number(poolShareMintAccBefore.supply) + VIRTUAL_SHARES)
     );
     expect(poolShareTokenFromAfter.amount).equals(
       poolShareTokenFromBefore.amount - BigInt(amount1.toString())
@@ -459,21 +526,20 @@ describe("flash-loan-mastery", () => {
         poolAuthority: poolAuthorityKey,
         instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
         tokenProgram: TOKEN_PROGRAM_ID,
+        poolConfig: poolConfigKey,
+        poolStats: poolStatsKey,
       })
       .instruction();
 
     const loanFees = amount1
       .mul(new BN(LOAN_FEE))
-      .div(new BN(LOAN_FEE_DENOMINATOR))
-      .div(new BN(ONE_HUNDRED));
+      .div(new BN(FEE_DENOMINATOR));
     const referralFee = amount1
       .mul(new BN(REFERRAL_FEE))
-      .div(new BN(LOAN_FEE_DENOMINATOR))
-      .div(new BN(ONE_HUNDRED));
+      .div(new BN(FEE_DENOMINATOR));
     const totalFees = amount1
       .mul(new BN(LOAN_FEE + REFERRAL_FEE))
-      .div(new BN(LOAN_FEE_DENOMINATOR))
-      .div(new BN(ONE_HUNDRED));
+      .div(new BN(FEE_DENOMINATOR));
     const repaymentAmount = amount1.add(totalFees);
     const repaymentAmountNoReferral = amount1.add(loanFees);
     const repayIx = await program.methods
@@ -485,6 +551,9 @@ describe("flash-loan-mastery", () => {
         poolAuthority: poolAuthorityKey,
         instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
         tokenProgram: TOKEN_PROGRAM_ID,
+        poolConfig: poolConfigKey,
+        poolShareMint: poolMint.publicKey,
+        poolStats: poolStatsKey,
       })
       .instruction();
 
@@ -540,6 +609,9 @@ describe("flash-loan-mastery", () => {
         poolAuthority: poolAuthorityKey,
         instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
         tokenProgram: TOKEN_PROGRAM_ID,
+        poolConfig: poolConfigKey,
+        poolShareMint: poolMint.publicKey,
+        poolStats: poolStatsKey,
       })
       .remainingAccounts([
         { pubkey: referralTokenTo, isSigner: false, isWritable: true },
@@ -578,6 +650,19 @@ describe("flash-loan-mastery", () => {
       repayerFromAfter.amount - BigInt(repaymentAmount.toNumber())
     ) /** referral fees have been charged */;
 
+    // pool statistics are updated
+    const poolStatsAccount = await program.account.poolStats.fetch(
+      poolStatsKey
+    );
+    expect(poolStatsAccount.totalLoans.toNumber()).eq(2);
+    expect(poolStatsAccount.totalBorrowed.toNumber()).eq(
+      amount1.toNumber() * 2
+    );
+    expect(poolStatsAccount.totalFees.toNumber()).eq(loanFees.toNumber() * 2);
+    expect(poolStatsAccount.totalReferralFees.toNumber()).eq(
+      referralFee.toNumber()
+    );
+
       // wrong repayment fails
       let success1 = true;
       try {
@@ -593,6 +678,8 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
               await program.methods
@@ -604,6 +691,9 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolShareMint: poolMint.publicKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
             ]
@@ -629,6 +719,8 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
               createTransferInstruction(repayerFrom, lenderFrom, wallet, 1337),
@@ -655,6 +747,8 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
               await program.methods
@@ -666,6 +760,9 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolShareMint: poolMint.publicKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
             ]
@@ -689,6 +786,9 @@ describe("flash-loan-mastery", () => {
                 poolAuthority: poolAuthorityKey,
                 instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                 tokenProgram: TOKEN_PROGRAM_ID,
+                poolConfig: poolConfigKey,
+                poolShareMint: poolMint.publicKey,
+                poolStats: poolStatsKey,
               })
               .instruction(),
           ]
@@ -710,6 +810,8 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
               await program.methods
@@ -721,6 +823,8 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction() /** borrow again */,
               await program.methods
@@ -732,6 +836,9 @@ describe("flash-loan-mastery", () => {
                   poolAuthority: poolAuthorityKey,
                   instructionsSysvar: SYSVAR_INSTRUCTIONS_PUBKEY,
                   tokenProgram: TOKEN_PROGRAM_ID,
+                  poolConfig: poolConfigKey,
+                  poolShareMint: poolMint.publicKey,
+                  poolStats: poolStatsKey,
                 })
                 .instruction(),
             ]
@@ -742,4 +849,220 @@ describe("flash-loan-mastery", () => {
       }
       expect(success4).to.be.false;
   });
+
+  it("set pool config", async () => {
+    const setPoolConfigIx = (loanFee: number, referralFee: number) =>
+      program.methods
+        .setPoolConfig(new BN(loanFee), new BN(referralFee))
+        .accountsStrict({
+          admin: wallet,
+          poolAuthority: poolAuthorityKey,
+          poolConfig: poolConfigKey,
+        })
+        .instruction();
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(await setPoolConfigIx(3000, 100))
+    );
+    const poolConfigAccount = await program.account.poolConfig.fetch(
+      poolConfigKey
+    );
+    expect(poolConfigAccount.loanFeeMillionths.toNumber()).eq(3000);
+    expect(poolConfigAccount.referralFeeMillionths.toNumber()).eq(100);
+
+    // fees outside of the bounds fail
+    let success1 = true;
+    try {
+      await program.provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(await setPoolConfigIx(20_000, 100))
+      );
+    } catch {
+      success1 = false;
+    }
+    expect(success1).to.be.false;
+
+    // restore the default fees
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await setPoolConfigIx(LOAN_FEE, REFERRAL_FEE)
+      )
+    );
+  });
+
+  it("transfer pool admin", async () => {
+    const newAdmin = new Keypair();
+    const proposeAdminIx = (admin: PublicKey, proposed: PublicKey) =>
+      program.methods
+        .proposeAdmin(proposed)
+        .accountsStrict({ admin, poolAuthority: poolAuthorityKey })
+        .instruction();
+    const acceptAdminIx = (pendingAdmin: PublicKey) =>
+      program.methods
+        .acceptAdmin()
+        .accountsStrict({ pendingAdmin, poolAuthority: poolAuthorityKey })
+        .instruction();
+
+    // only the pending admin can accept
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await proposeAdminIx(wallet, newAdmin.publicKey)
+      )
+    );
+    let success1 = true;
+    try {
+      await program.provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(await acceptAdminIx(wallet))
+      );
+    } catch {
+      success1 = false;
+    }
+    expect(success1).to.be.false;
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await acceptAdminIx(newAdmin.publicKey)
+      ),
+      [newAdmin]
+    );
+    let poolAuthorityAccount = await program.account.poolAuthority.fetch(
+      poolAuthorityKey
+    );
+    expect(poolAuthorityAccount.admin.equals(newAdmin.publicKey)).to.be.true;
+    expect(poolAuthorityAccount.pendingAdmin.equals(PublicKey.default)).to.be
+      .true;
+
+    // the old admin can no longer propose
+    let success2 = true;
+    try {
+      await program.provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(await proposeAdminIx(wallet, wallet))
+      );
+    } catch {
+      success2 = false;
+    }
+    expect(success2).to.be.false;
+
+    // hand the pool back
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await proposeAdminIx(newAdmin.publicKey, wallet),
+        await acceptAdminIx(wallet)
+      ),
+      [newAdmin]
+    );
+    poolAuthorityAccount = await program.account.poolAuthority.fetch(
+      poolAuthorityKey
+    );
+    expect(poolAuthorityAccount.admin.equals(wallet)).to.be.true;
+  });
+
+  it("pause pool", async () => {
+    const guardian = new Keypair();
+    const walletToken = await getAssociatedTokenAddress(
+      tokenMint.publicKey,
+      wallet
+    );
+    const poolToken = await getAssociatedTokenAddress(
+      tokenMint.publicKey,
+      poolAuthorityKey,
+      true
+    );
+    const walletPoolShareToken = await getAssociatedTokenAddress(
+      poolMint.publicKey,
+      wallet
+    );
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await program.methods
+          .setGuardian(guardian.publicKey)
+          .accountsStrict({ admin: wallet, poolAuthority: poolAuthorityKey })
+          .instruction()
+      )
+    );
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await program.methods
+          .pause()
+          .accountsStrict({
+            guardian: guardian.publicKey,
+            poolAuthority: poolAuthorityKey,
+          })
+          .instruction()
+      ),
+      [guardian]
+    );
+    const poolAuthorityAccount = await program.account.poolAuthority.fetch(
+      poolAuthorityKey
+    );
+    expect(poolAuthorityAccount.guardian.equals(guardian.publicKey)).to.be
+      .true;
+    expect(poolAuthorityAccount.paused).eq(1);
+
+    // deposits fail while paused
+    let success1 = true;
+    try {
+      await program.provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          await program.methods
+            .deposit(new BN(1_000))
+            .accountsStrict({
+              depositor: wallet,
+              tokenFrom: walletToken,
+              tokenTo: poolToken,
+              poolShareTokenTo: walletPoolShareToken,
+              poolShareMint: poolMint.publicKey,
+              poolAuthority: poolAuthorityKey,
+              tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .instruction()
+        )
+      );
+    } catch {
+      success1 = false;
+    }
+    expect(success1).to.be.false;
+
+    // withdrawals still work while paused
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        await program.methods
+          .withdraw(new BN(50_000))
+          .accountsStrict({
+            withdrawer: wallet,
+            tokenFrom: poolToken,
+            tokenTo: walletToken,
+            poolShareTokenFrom: walletPoolShareToken,
+            poolShareMint: poolMint.publicKey,
+            poolAuthority: poolAuthorityKey,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .instruction()
+      )
+    );
+
+    // only the admin can unpause
+    const unpauseIx = (admin: PublicKey) =>
+      program.methods
+        .unpause()
+        .accountsStrict({ admin, poolAuthority: poolAuthorityKey })
+        .instruction();
+    let success2 = true;
+    try {
+      await program.provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(await unpauseIx(guardian.publicKey)),
+        [guardian]
+      );
+    } catch {
+      success2 = false;
+    }
+    expect(success2).to.be.false;
+
+    await program.provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(await unpauseIx(wallet))
+    );
+    expect(
+      (await program.account.poolAuthority.fetch(poolAuthorityKey)).paused
+    ).eq(0);
+  });
 });